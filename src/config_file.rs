@@ -0,0 +1,100 @@
+use std::fmt;
+use std::fs;
+
+use toml;
+
+/// Settings read from the `--config` TOML file. Keys are named after the
+/// `Config` fields they set, and anything left out falls back to the
+/// command line defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    all: Option<bool>,
+    prefetch_path: Option<String>,
+    index_path: Option<String>,
+    stats_db: Option<String>,
+    stats_retention: Option<Value>,
+    upstream: Option<String>,
+    index: Option<String>,
+    extern_url: Option<String>,
+    port: Option<u16>,
+    listen: Option<Vec<String>>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    refresh_interval: Option<Value>,
+    connect_timeout: Option<Value>,
+    read_timeout: Option<Value>,
+    threads: Option<u32>,
+    max_cache_size: Option<Value>,
+    eviction_interval: Option<Value>,
+    shutdown_timeout: Option<Value>,
+    pinned: Option<Vec<String>>,
+    log_level: Option<String>,
+}
+
+/// Durations and sizes can be written either as a plain number (seconds or
+/// bytes) or in the same form as on the command line, ex: "2 hours", "20G".
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    Number(u64),
+    Text(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Text(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl FileConfig {
+    pub fn load(path: &str) -> Result<FileConfig, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    }
+
+    /// The value for a command line option, by its argument name.
+    pub fn value(&self, arg: &str) -> Option<String> {
+        let number = |n: Option<u64>| n.map(|n| n.to_string());
+        let value = |v: &Option<Value>| v.as_ref().map(|v| v.to_string());
+        match arg {
+            "prefetch" => self.prefetch_path.clone(),
+            "index" => self.index_path.clone(),
+            "stats-db" => self.stats_db.clone(),
+            "stats-retention" => value(&self.stats_retention),
+            "upstream" => self.upstream.clone(),
+            "git" => self.index.clone(),
+            "extern-url" => self.extern_url.clone(),
+            "tls-cert" => self.tls_cert.clone(),
+            "tls-key" => self.tls_key.clone(),
+            "port" => number(self.port.map(u64::from)),
+            "refresh" => value(&self.refresh_interval),
+            "connect-timeout" => value(&self.connect_timeout),
+            "read-timeout" => value(&self.read_timeout),
+            "threads" => number(self.threads.map(u64::from)),
+            "max-cache-size" => value(&self.max_cache_size),
+            "eviction-interval" => value(&self.eviction_interval),
+            "shutdown-timeout" => value(&self.shutdown_timeout),
+            _ => None,
+        }
+    }
+
+    pub fn all(&self) -> bool {
+        self.all.unwrap_or(false)
+    }
+
+    pub fn pinned(&self) -> Vec<String> {
+        self.pinned.clone().unwrap_or_default()
+    }
+
+    pub fn listen(&self) -> Vec<String> {
+        self.listen.clone().unwrap_or_default()
+    }
+
+    pub fn log_level(&self) -> Option<&str> {
+        self.log_level.as_deref()
+    }
+}
@@ -1,163 +1,629 @@
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::{self, File};
 // use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::os::fd::AsRawFd;
 use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use cargo_lock::Lockfile;
+use libc;
 use scoped_threadpool::Pool;
 use serde_json;
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
+use inflight::{Claim, InFlight};
+use metrics;
 use super::Config;
 
 #[derive(Debug, Deserialize)]
 pub struct Package {
     name: String,
     vers: String,
+    cksum: String,
 }
 
+#[derive(Debug)]
+pub enum FetchError {
+    /// Upstream doesn't have this crate version
+    NotFound,
+    /// Upstream couldn't be reached or is temporarily refusing requests
+    Unavailable(String),
+    /// Upstream answered with an unexpected HTTP status
+    Upstream(u16),
+    /// Couldn't download or read the crate file
+    Io(io::Error),
+    /// The requested version isn't listed in the index
+    NotInIndex,
+    /// The downloaded file doesn't match the index's SHA-256 checksum
+    Checksum { expected: String, actual: String },
+}
+
+impl FetchError {
+    fn from_status(code: u16) -> FetchError {
+        match code {
+            403 | 404 | 410 => FetchError::NotFound,
+            429 | 503 | 504 => FetchError::Unavailable(format!("upstream returned {}", code)),
+            code => FetchError::Upstream(code),
+        }
+    }
+
+    /// Short label for the kind of failure, as used in metrics.
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            FetchError::NotFound => "not_found",
+            FetchError::Unavailable(_) => "unavailable",
+            FetchError::Upstream(_) => "upstream",
+            FetchError::Io(_) => "io",
+            FetchError::NotInIndex => "not_in_index",
+            FetchError::Checksum { .. } => "checksum",
+        }
+    }
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FetchError::NotFound => write!(f, "not found upstream"),
+            FetchError::Unavailable(ref reason) => write!(f, "upstream unavailable: {}", reason),
+            FetchError::Upstream(code) => write!(f, "upstream returned {}", code),
+            FetchError::Io(ref e) => write!(f, "{}", e),
+            FetchError::NotInIndex => write!(f, "not listed in the index"),
+            FetchError::Checksum {
+                ref expected,
+                ref actual,
+            } => write!(f, "checksum mismatch, expected {} got {}", expected, actual),
+        }
+    }
+}
+
+impl Clone for FetchError {
+    fn clone(&self) -> FetchError {
+        match *self {
+            FetchError::NotFound => FetchError::NotFound,
+            FetchError::Unavailable(ref reason) => FetchError::Unavailable(reason.clone()),
+            FetchError::Upstream(code) => FetchError::Upstream(code),
+            FetchError::Io(ref e) => FetchError::Io(io::Error::new(e.kind(), e.to_string())),
+            FetchError::NotInIndex => FetchError::NotInIndex,
+            FetchError::Checksum {
+                ref expected,
+                ref actual,
+            } => FetchError::Checksum {
+                expected: expected.clone(),
+                actual: actual.clone(),
+            },
+        }
+    }
+}
+
+impl From<io::Error> for FetchError {
+    fn from(e: io::Error) -> FetchError {
+        FetchError::Io(e)
+    }
+}
+
+/// HTTP client for the upstream crate source. Clones share the same pool of
+/// keep-alive connections and the same set of in-flight downloads.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    agent: ureq::Agent,
+    url: String,
+    in_flight: Arc<InFlight>,
+}
+
+impl Upstream {
+    pub fn new(config: &Config) -> Upstream {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(config.connect_timeout)
+            .timeout_read(config.read_timeout)
+            .max_idle_connections_per_host(config.threads as usize)
+            .user_agent(concat!("cargo-cacher/", crate_version!()))
+            .build();
+        Upstream {
+            agent,
+            url: config.upstream.clone(),
+            in_flight: Arc::new(InFlight::default()),
+        }
+    }
+
+    /// Registers interest in downloading a crate, see `InFlight`.
+    pub fn claim(&self, crate_name: &str, crate_version: &str) -> Claim {
+        InFlight::claim(&self.in_flight, crate_name, crate_version)
+    }
+
+    fn crate_url(&self, crate_name: &str, crate_version: &str) -> String {
+        format!(
+            "{}{}/{}-{}.crate",
+            self.url, crate_name, crate_name, crate_version
+        )
+    }
+}
+
+/// Downloads a crate into `path`. The download goes to a temporary file next
+/// to `path` and is only renamed into place once it is complete and verified,
+/// so `path` either doesn't exist or holds a good crate.
 pub fn fetch(
-    path: &PathBuf,
-    upstream: &str,
-    index_path: &str,
+    path: &Path,
+    config: &Config,
+    upstream: &Upstream,
     crate_name: &str,
     crate_version: &str,
-) -> Result<ExitStatus, io::Error> {
+) -> Result<(), FetchError> {
+    start(path, config, upstream, crate_name, crate_version)
+        .and_then(|download| download.finish(None))
+        .map(|_| ())
+}
+
+/// Requests a crate from upstream, failing early if upstream doesn't have it.
+/// The body is read by `Download::finish`.
+pub fn start(
+    path: &Path,
+    config: &Config,
+    upstream: &Upstream,
+    crate_name: &str,
+    crate_version: &str,
+) -> Result<Download, FetchError> {
     debug!("Fetching {}(v: {})", crate_name, crate_version);
-    let url = format!(
-        "{}{}/{}-{}.crate",
-        upstream, crate_name, crate_name, crate_version
-    );
-    trace!("Fetching from {}", url);
-    let _ = fs::create_dir_all(PathBuf::from(format!(
-        "{}/crates/{}",
-        index_path, crate_name
-    )));
-    Command::new("curl")
-        .arg("-o")
-        .arg(&path) // Save to disk
-        .arg("-L") // Follow redirects
-        .arg("-s") // Quietly!
-        .arg(url)
-        .status()
-}
-
-pub fn size(path: &PathBuf) -> u64 {
-    match fs::metadata(path) {
-        Ok(metadata) => metadata.len(),
-        _ => 0,
-    }
-}
-
-fn try_fetch(config: &Config, crate_name: &str, crate_version: &str) {
+    let result = checksum(&config.git_index_path, crate_name, crate_version)
+        .ok_or(FetchError::NotInIndex)
+        .and_then(|expected| {
+            let url = upstream.crate_url(crate_name, crate_version);
+            trace!("Fetching from {}", url);
+            let started = Instant::now();
+            let response = match upstream.agent.get(&url).call() {
+                Ok(response) => response,
+                Err(ureq::Error::Status(code, _)) => return Err(FetchError::from_status(code)),
+                Err(ureq::Error::Transport(e)) => {
+                    return Err(FetchError::Unavailable(e.to_string()))
+                }
+            };
+            let len = response
+                .header("Content-Length")
+                .and_then(|l| l.parse().ok());
+            let _ = fs::create_dir_all(PathBuf::from(format!(
+                "{}/crates/{}",
+                config.index_path, crate_name
+            )));
+            let tmp = temp_path(path);
+            let file = create_locked(&tmp)?;
+            Ok(Download {
+                name: format!("{}:{}", crate_name, crate_version),
+                body: Box::new(response.into_reader()),
+                len,
+                file,
+                tmp,
+                path: path.to_path_buf(),
+                hasher: Sha256::new(),
+                expected,
+                upstream_time: started.elapsed(),
+                committed: false,
+            })
+        });
+    if let Err(ref e) = result {
+        warn!("Couldn't fetch {}:{}: {}", crate_name, crate_version, e);
+        // Upstream was never asked
+        if !matches!(*e, FetchError::NotInIndex) {
+            metrics::fetch_failed(e.reason());
+        }
+    }
+    result
+}
+
+/// A crate being downloaded from upstream into a temporary file, which only
+/// becomes the cached crate once the whole body arrived and matched the
+/// index's checksum.
+pub struct Download {
+    name: String,
+    body: Box<dyn Read + Send>,
+    len: Option<u64>,
+    file: File,
+    tmp: PathBuf,
+    path: PathBuf,
+    hasher: Sha256,
+    expected: String,
+    /// Time spent waiting on upstream so far
+    upstream_time: Duration,
+    committed: bool,
+}
+
+/// A download that made it into the cache.
+pub struct Fetched {
+    pub size: u64,
+    /// Time spent waiting on upstream, as opposed to on the disk or client
+    pub upstream_time: Duration,
+}
+
+impl Download {
+    /// Size of the crate, if upstream told us.
+    pub fn len(&self) -> Option<u64> {
+        self.len
+    }
+
+    /// Reads the rest of the body into the cache, copying it to `client` as
+    /// it arrives. A client that goes away doesn't stop the download.
+    /// The last chunk only goes to `client` once the checksum matched, so a
+    /// bad body reaches it short of its `Content-Length`, never complete.
+    pub fn finish(mut self, mut client: Option<&mut dyn Write>) -> Result<Fetched, FetchError> {
+        let mut held = vec![];
+        let result = self.copy(&mut client, &mut held).and_then(|written| {
+            self.file.sync_all()?;
+            let actual = format!("{:x}", self.hasher.clone().finalize());
+            if !actual.eq_ignore_ascii_case(&self.expected) {
+                return Err(FetchError::Checksum {
+                    expected: self.expected.clone(),
+                    actual,
+                });
+            }
+            fs::rename(&self.tmp, &self.path)?;
+            self.committed = true;
+            metrics::CACHE_SIZE.add(written);
+            self.send(&mut client, &held);
+            Ok(Fetched {
+                size: written,
+                upstream_time: self.upstream_time,
+            })
+        });
+        if let Err(ref e) = result {
+            warn!("Discarding {}: {}", self.name, e);
+            metrics::fetch_failed(e.reason());
+        }
+        result
+    }
+
+    fn copy(
+        &mut self,
+        client: &mut Option<&mut dyn Write>,
+        held: &mut Vec<u8>,
+    ) -> Result<u64, FetchError> {
+        // Tell read failures (upstream) apart from write failures (local disk)
+        let mut buf = [0; 64 * 1024];
+        let mut written = 0;
+        loop {
+            let started = Instant::now();
+            let read = self.body.read(&mut buf);
+            self.upstream_time += started.elapsed();
+            let len = match read {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(FetchError::Unavailable(e.to_string())),
+            };
+            self.file.write_all(&buf[..len])?;
+            self.hasher.update(&buf[..len]);
+            written += len as u64;
+            self.send(client, held);
+            held.clear();
+            held.extend_from_slice(&buf[..len]);
+        }
+        Ok(written)
+    }
+
+    fn send(&self, client: &mut Option<&mut dyn Write>, chunk: &[u8]) {
+        let failed = match *client {
+            Some(ref mut c) => c.write_all(chunk).is_err(),
+            None => false,
+        };
+        if failed {
+            debug!("Client went away, finishing {} for the cache", self.name);
+            *client = None;
+        }
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// A unique hidden file alongside `path` to download into.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(
+        ".{}.{}-{}{}",
+        name,
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst),
+        PARTIAL_SUFFIX
+    ))
+}
+
+/// Creates a temp file to download into, locked for as long as it is open
+/// so the sweep can tell it apart from one left behind.
+fn create_locked(tmp: &Path) -> io::Result<File> {
+    let file = File::create(tmp)?;
+    try_lock(&file)?;
+    Ok(file)
+}
+
+fn try_lock(file: &File) -> io::Result<()> {
+    match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Removes downloads left behind by a process that was interrupted. Those
+/// still locked by a server or job running on the same storage are left
+/// alone.
+pub fn remove_partial_downloads(crate_path: &str) {
+    for entry in WalkDir::new(crate_path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .filter(|e| e.file_name().to_string_lossy().ends_with(PARTIAL_SUFFIX))
+        .filter(|e| File::open(e.path()).and_then(|f| try_lock(&f)).is_ok())
+    {
+        info!("Removing partial download {:?}", entry.path());
+        if let Err(e) = fs::remove_file(entry.path()) {
+            warn!("Couldn't remove {:?}: {}", entry.path(), e);
+        }
+    }
+}
+
+/// Looks up the expected SHA-256 of a crate version in the local index.
+pub fn checksum(git_index_path: &str, crate_name: &str, crate_version: &str) -> Option<String> {
+    let f = File::open(index_file(git_index_path, crate_name)?).ok()?;
+    io::BufReader::new(f)
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<Package>(&line).ok())
+        .find(|package| package.vers == crate_version)
+        .map(|package| package.cksum)
+}
+
+fn sha256(path: &Path) -> io::Result<String> {
+    let mut f = File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut f, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Checks a cached crate file against the checksum recorded in the index.
+pub fn verify(
+    path: &Path,
+    git_index_path: &str,
+    crate_name: &str,
+    crate_version: &str,
+) -> Result<(), FetchError> {
+    let expected = match checksum(git_index_path, crate_name, crate_version) {
+        Some(cksum) => cksum,
+        None => return Err(FetchError::NotInIndex),
+    };
+    let actual = sha256(path)?;
+    if actual.eq_ignore_ascii_case(&expected) {
+        Ok(())
+    } else {
+        Err(FetchError::Checksum { expected, actual })
+    }
+}
+
+/// Location of a crate's file inside the registry index, following the
+/// `1/`, `2/`, `3/a/` and `ab/cd/` layout used by crates.io. Returns `None`
+/// for names that could not be a valid crate.
+pub fn index_file(git_index_path: &str, crate_name: &str) -> Option<PathBuf> {
+    if crate_name.is_empty()
+        || !crate_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return None;
+    }
+    let name = crate_name.to_lowercase();
+    let mut path = PathBuf::from(git_index_path);
+    match name.len() {
+        1 => path.push("1"),
+        2 => path.push("2"),
+        3 => {
+            path.push("3");
+            path.push(&name[..1]);
+        }
+        _ => {
+            path.push(&name[..2]);
+            path.push(&name[2..4]);
+        }
+    }
+    path.push(&name);
+    Some(path)
+}
+
+/// Fetches a crate into the cache unless it is already there, returning
+/// whether it is now cached.
+fn try_fetch(config: &Config, upstream: &Upstream, crate_name: &str, crate_version: &str) -> bool {
     let path = PathBuf::from(format!(
         "{}/crates/{}/{}",
         config.index_path, crate_name, crate_version
     ));
     if path.exists() {
         trace!("{}:{} is already fetched", crate_name, crate_version);
-    } else {
-        match fetch(
-            &path,
-            &config.upstream,
-            &config.index_path,
-            &crate_name,
-            &crate_version,
-        ) {
-            Ok(_) => {}
-            Err(e) => error!("Couldn't fetch {}/{}: {:?}", crate_name, crate_version, e),
+        return true;
+    }
+    let result = match upstream.claim(crate_name, crate_version) {
+        Claim::Leader(ticket) => {
+            let result = if path.exists() {
+                Ok(())
+            } else {
+                fetch(&path, config, upstream, crate_name, crate_version)
+            };
+            ticket.finish(&result);
+            result
         }
+        Claim::Follower(flight) => flight.wait(),
+    };
+    if let Err(ref e) = result {
+        error!("Couldn't fetch {}/{}: {}", crate_name, crate_version, e);
     }
+    result.is_ok()
 }
 
-pub fn pre_fetch(config: &Config) {
-    fetch_all(&config);
-    let config = config.clone();
-    if let Some(_) = config.prefetch_path {
-        let prefetch_path = config.prefetch_path.clone().unwrap();
-        let prefetch_ext = Path::new(&prefetch_path).extension();
-        if prefetch_ext == Some(OsStr::new("lock")) {
-            thread::spawn(move || fetch_lock(&config));
-            return;
-        }
+/// Starts fetching the configured crates in the background.
+pub fn pre_fetch(config: &Config, upstream: &Upstream) {
+    fetch_all(config, upstream);
+    if let Some(prefetch_path) = config.prefetch_path.clone() {
+        let config = config.clone();
+        let upstream = upstream.clone();
         thread::spawn(move || {
-            debug!("Prefetching file at {}!", prefetch_path);
-            if let Ok(f) = File::open(prefetch_path) {
-                let reader = io::BufReader::new(f);
-                for line in reader.lines().filter(|l| l.is_ok()).map(|l| l.unwrap()) {
-                    let mut split = line.split("=");
-                    if let Some(crate_name) = split.next() {
-                        if let Some(crate_version) = split.next() {
-                            try_fetch(&config, crate_name, crate_version);
-                        }
-                    }
-                }
+            if let Err(e) = prefetch_file(&config, &upstream, &prefetch_path) {
+                error!("{}", e);
             }
         });
     }
 }
 
-pub fn fetch_all(config: &Config) {
+/// Fetches the crates listed in a `Cargo.lock`, or in a file of
+/// `crate_name=version` lines. Returns how many couldn't be fetched, or an
+/// error if the file couldn't be read.
+pub fn prefetch_file(config: &Config, upstream: &Upstream, prefetch_path: &str) -> Result<usize, String> {
+    debug!("Prefetching file at {}!", prefetch_path);
+    if Path::new(prefetch_path).extension() == Some(OsStr::new("lock")) {
+        return fetch_lock(config, upstream, prefetch_path);
+    }
+    let f = File::open(prefetch_path).map_err(|e| format!("Couldn't read {}: {}", prefetch_path, e))?;
+    let mut failed = 0;
+    for line in io::BufReader::new(f).lines().map_while(Result::ok) {
+        let mut split = line.split('=');
+        if let (Some(crate_name), Some(crate_version)) = (split.next(), split.next()) {
+            if !try_fetch(config, upstream, crate_name.trim(), crate_version.trim()) {
+                failed += 1;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Starts fetching every crate in the index in the background, if `--all`
+/// was given.
+pub fn fetch_all(config: &Config, upstream: &Upstream) {
     if !config.all {
         return;
     }
     let config = config.clone();
-    thread::spawn(move || {
-        let mut pool = Pool::new(config.threads);
-        debug!("Spawned batch fetch thread");
-        let mut git_path = config.index_path.clone();
-        git_path.push_str("/index");
-        pool.scoped(|scope| {
-            for entry in WalkDir::new(git_path)
-                .into_iter()
-                .filter_map(|e| e.ok())
-                .filter(|f| !f.path().to_str().unwrap().contains(".git"))
-                .filter(|f| f.file_type().is_file())
-                .filter(|f| f.file_name() != "config.json")
-            {
-                trace!("Found crate info file at {:?}", entry.path());
-
-                let config = config.clone();
-                scope.execute(move || {
-                    if let Ok(f) = File::open(entry.path()) {
-                        let reader = io::BufReader::new(f);
-                        for line in reader.lines().filter_map(|l| l.ok()) {
-                            match serde_json::from_str::<Package>(&line) {
-                                // match json::decode::<Package>(&line) {
-                                Ok(package) => {
-                                    trace!("Found package: {:?}", package);
-
-                                    try_fetch(&config, &package.name, &package.vers);
+    let upstream = upstream.clone();
+    thread::spawn(move || fetch_index(&config, &upstream));
+}
+
+/// Fetches every crate in the index. Returns how many couldn't be fetched.
+pub fn fetch_index(config: &Config, upstream: &Upstream) -> usize {
+    let failed = AtomicUsize::new(0);
+    let mut pool = Pool::new(config.threads);
+    debug!("Spawned batch fetch thread");
+    pool.scoped(|scope| {
+        for entry in WalkDir::new(&config.git_index_path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|f| !f.path().to_str().unwrap().contains(".git"))
+            .filter(|f| f.file_type().is_file())
+            .filter(|f| f.file_name() != "config.json")
+        {
+            trace!("Found crate info file at {:?}", entry.path());
+
+            let failed = &failed;
+            scope.execute(move || {
+                if let Ok(f) = File::open(entry.path()) {
+                    let reader = io::BufReader::new(f);
+                    for line in reader.lines().map_while(Result::ok) {
+                        match serde_json::from_str::<Package>(&line) {
+                            // match json::decode::<Package>(&line) {
+                            Ok(package) => {
+                                trace!("Found package: {:?}", package);
+
+                                if !try_fetch(config, upstream, &package.name, &package.vers) {
+                                    failed.fetch_add(1, Ordering::Relaxed);
                                 }
-                                Err(e) => warn!(
-                                    "Had a problem with \"{}\" / {:?}: {:?}",
-                                    line,
-                                    entry.path(),
-                                    e
-                                ),
-                            };
-                        }
+                            }
+                            Err(e) => warn!(
+                                "Had a problem with \"{}\" / {:?}: {:?}",
+                                line,
+                                entry.path(),
+                                e
+                            ),
+                        };
                     }
-                });
-            }
-        });
-
-        debug!("Finished background fetch all");
+                }
+            });
+        }
     });
+
+    debug!("Finished background fetch all");
+    failed.into_inner()
 }
 
-fn fetch_lock(config: &Config) {
-    let prefetch_path = config.prefetch_path.clone().unwrap();
-    let lockfile = Lockfile::load(prefetch_path).unwrap();
+fn fetch_lock(config: &Config, upstream: &Upstream, prefetch_path: &str) -> Result<usize, String> {
+    let lockfile = Lockfile::load(prefetch_path).map_err(|e| format!("Couldn't read {}: {}", prefetch_path, e))?;
 
+    let mut failed = 0;
     for package in lockfile.packages {
+        // Workspace members and git dependencies don't come from a registry
+        if !package.source.as_ref().is_some_and(|source| source.is_registry()) {
+            trace!("Skipping {} v{}, it isn't from a registry", package.name, package.version);
+            continue;
+        }
         let name = package.name.as_str();
         let version = package.version.to_string();
         trace!("Resolved package: {} v{}", name, version);
-        try_fetch(config, name, &version);
+        if !try_fetch(config, upstream, name, &version) {
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_file_layout() {
+        let cases = [
+            ("a", Some("/index/1/a")),
+            ("cc", Some("/index/2/cc")),
+            ("syn", Some("/index/3/s/syn")),
+            ("serde", Some("/index/se/rd/serde")),
+            ("Serde_JSON", Some("/index/se/rd/serde_json")),
+            ("cargo-cacher", Some("/index/ca/rg/cargo-cacher")),
+            ("", None),
+            ("../config.json", None),
+            ("se/rd", None),
+            ("s\u{e9}rde", None),
+        ];
+        for &(name, expected) in &cases {
+            assert_eq!(index_file("/index", name), expected.map(PathBuf::from), "{}", name);
+        }
+    }
+
+    #[test]
+    fn sweep_keeps_downloads_in_progress() {
+        let dir = std::env::temp_dir().join(format!("cargo-cacher-sweep-{}", process::id()));
+        let crate_dir = dir.join("serde");
+        fs::create_dir_all(&crate_dir).unwrap();
+        let path = crate_dir.join("1.0.0");
+        File::create(&path).unwrap();
+        // Left behind by a crashed run, which had the same PID in a container
+        let stale = crate_dir.join(format!(".1.0.0.{}-999999.partial", process::id()));
+        File::create(&stale).unwrap();
+        let live = temp_path(&path);
+        let _download = create_locked(&live).unwrap();
+
+        remove_partial_downloads(dir.to_str().unwrap());
+
+        assert!(!stale.exists());
+        assert!(live.exists());
+        assert!(path.exists());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,138 @@
+use std::cmp;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::thread::{self, sleep};
+use std::time::UNIX_EPOCH;
+
+use walkdir::WalkDir;
+
+use metrics;
+use stats::Database;
+use Config;
+
+#[derive(Debug)]
+pub struct CachedCrate {
+    pub name: String,
+    pub version: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Seconds since the epoch this crate was last downloaded or served
+    last_used: i64,
+}
+
+impl CachedCrate {
+    /// Deletes the crate file, and its directory once no versions are left.
+    pub fn remove(&self) -> io::Result<()> {
+        fs::remove_file(&self.path)?;
+        if let Some(dir) = self.path.parent() {
+            // Only succeeds once the crate has no versions left
+            let _ = fs::remove_dir(dir);
+        }
+        Ok(())
+    }
+}
+
+/// Starts the background thread measuring the crate cache and keeping it
+/// under `max_cache_size`, if one was configured.
+pub fn init_eviction(config: &Config, db: &Database) {
+    let config = config.clone();
+    let db = db.clone();
+    thread::spawn(move || loop {
+        evict(&config, &db);
+        sleep(config.eviction_interval);
+    });
+}
+
+/// Removes the least recently served crates until the cache fits within
+/// `max_cache_size`. Pinned crates are never removed.
+pub fn evict(config: &Config, db: &Database) {
+    let mut crates = cached_crates(&config.crate_path);
+    let mut total: u64 = crates.iter().map(|c| c.size).sum();
+    // Keep the metric current even without a limit
+    metrics::CACHE_SIZE.set(total);
+    let max_size = match config.max_cache_size {
+        Some(max_size) => max_size,
+        None => return,
+    };
+    debug!("Crate cache is using {} of {} bytes", total, max_size);
+    if total <= max_size {
+        return;
+    }
+
+    let last_served = db.last_served();
+    for c in &mut crates {
+        if let Some(served) = last_served.get(&(c.name.clone(), c.version.clone())) {
+            c.last_used = cmp::max(c.last_used, *served);
+        }
+    }
+    crates.retain(|c| !is_pinned(&config.pinned, &c.name, &c.version));
+    crates.sort_by_key(|c| c.last_used);
+
+    let mut removed = 0;
+    for c in crates {
+        if total <= max_size {
+            break;
+        }
+        trace!("Evicting {}:{}", c.name, c.version);
+        match c.remove() {
+            Ok(_) => {
+                total -= c.size;
+                removed += 1;
+            }
+            Err(e) => warn!("Couldn't evict {:?}: {}", c.path, e),
+        }
+    }
+    metrics::CACHE_SIZE.set(total);
+    info!("Evicted {} crates, cache is now {} bytes", removed, total);
+    if total > max_size {
+        warn!(
+            "Crate cache is still over budget ({} > {} bytes), are too many crates pinned?",
+            total, max_size
+        );
+    }
+}
+
+/// Pins are either a crate name, covering every version, or `name=version`.
+fn is_pinned(pinned: &[String], crate_name: &str, crate_version: &str) -> bool {
+    pinned.iter().any(|pin| {
+        let mut split = pin.splitn(2, '=');
+        let name = split.next().unwrap_or("");
+        name == crate_name && split.next().map(|v| v == crate_version).unwrap_or(true)
+    })
+}
+
+/// Bytes used by the crates in the cache.
+pub fn cache_size(crate_path: &str) -> u64 {
+    cached_crates(crate_path).iter().map(|c| c.size).sum()
+}
+
+/// Every crate file in the cache, skipping downloads in progress.
+pub fn cached_crates(crate_path: &str) -> Vec<CachedCrate> {
+    WalkDir::new(crate_path)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        // Skip downloads in progress
+        .filter(|e| !e.file_name().to_string_lossy().starts_with('.'))
+        .filter_map(|e| {
+            let metadata = e.metadata().ok()?;
+            let name = e.path().parent()?.file_name()?.to_string_lossy().into_owned();
+            let last_used = metadata
+                .modified()
+                .ok()
+                .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            Some(CachedCrate {
+                name,
+                version: e.file_name().to_string_lossy().into_owned(),
+                path: e.path().to_path_buf(),
+                size: metadata.len(),
+                last_used,
+            })
+        })
+        .collect()
+}
@@ -13,10 +13,12 @@ use iron::response::BodyReader;
 
 use iron::mime::{Mime, TopLevel, SubLevel};
 
+use metrics;
 use Config;
 
 pub fn git(req: &mut Request, config: &Config) -> IronResult<Response> {
     debug!("Raw GIT request: {:?}", req);
+    metrics::GIT_INDEX_REQUESTS.inc();
     // let content_type: &str = match req.headers.get::<ContentType>() {
     //     Some(s) => {
     //         s.get_param("Content-Type")
@@ -79,13 +81,11 @@ pub fn git(req: &mut Request, config: &Config) -> IronResult<Response> {
             Ok(s) => s,
             _ => break,
         };
-        if line == "" || line == "\r" {
+        if line.is_empty() || line == "\r" {
             break;
         }
 
-        let mut parts = line.splitn(2, ':');
-        let key = parts.next().unwrap();
-        let value = parts.next().unwrap();
+        let (key, value) = line.split_once(':').unwrap();
         let value = &value[1..];
         headers.entry(key.to_string())
             .or_insert(Vec::new())
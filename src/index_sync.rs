@@ -1,36 +1,120 @@
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::os::unix::process::CommandExt;
 use std::process::{Command, Stdio};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::thread::{self, sleep};
+use std::time::Instant;
+
+use libc;
 
 use super::Config;
-use crates::fetch_all;
+use crates::{fetch_all, Upstream};
+use metrics;
+use stats;
 
-pub fn init_sync(git_path: PathBuf, config: &Config) {
+pub fn init_sync(git_path: PathBuf, config: &Config, upstream: &Upstream) {
     let config = config.clone();
+    let upstream = upstream.clone();
     git_sync(&git_path, &config.index, &config.extern_url);
     thread::spawn(move || loop {
         sleep(config.refresh_interval);
         git_sync(&git_path, &config.index, &config.extern_url);
         if config.all {
-            fetch_all(&config);
+            fetch_all(&config, &upstream);
         }
     });
 }
 
-pub fn git_sync(git_path: &PathBuf, index_path: &str, extern_url: &str) {
+/// The registry `config.json` pointing cargo back at this cacher.
+pub fn registry_config(extern_url: &str) -> String {
+    format!(
+        "{{
+  \"dl\": \"{0}/api/v1/crates\",
+  \"api\": \"{0}/\"
+}}
+",
+        extern_url
+    )
+}
+
+/// Held while the index is being synced.
+static SYNCING: Mutex<()> = Mutex::new(());
+
+/// Waits for a sync in progress to finish, and holds off new ones for as
+/// long as the guard is kept.
+pub fn pause() -> MutexGuard<'static, ()> {
+    SYNCING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Keeps a server and a one-shot job sharing the storage from syncing at the
+/// same time, until the file is dropped.
+fn lock(git_path: &Path) -> Option<File> {
+    let f = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(git_path.with_extension("lock"))
+        .ok()?;
+    match unsafe { libc::flock(f.as_raw_fd(), libc::LOCK_EX) } {
+        0 => Some(f),
+        _ => None,
+    }
+}
+
+/// Syncs the index, returning whether it succeeded.
+pub fn git_sync(git_path: &PathBuf, index_path: &str, extern_url: &str) -> bool {
+    let _syncing = pause();
+    let _lock = lock(git_path);
+    let start = Instant::now();
+    let synced = sync(git_path, index_path, extern_url);
+    metrics::INDEX_SYNCS.inc();
+    metrics::INDEX_SYNC_DURATION.set(start.elapsed().as_millis() as u64);
+    if synced {
+        metrics::INDEX_SYNC_LAST_SUCCESS.set(stats::now() as u64);
+    } else {
+        metrics::INDEX_SYNC_FAILURES.inc();
+    }
+    synced
+}
+
+/// Runs git in its own process group, so that Ctrl-C in a terminal doesn't
+/// interrupt it halfway through a rebase. Shutting down waits for it instead.
+fn git() -> Command {
+    let mut command = Command::new("git");
+    command.process_group(0);
+    command
+}
+
+/// Pulls or clones the index for a one-shot job. `config.json` is left to
+/// the server, which knows its own `extern_url`.
+pub fn update(git_path: &PathBuf, index_path: &str) -> bool {
+    let _syncing = pause();
+    let _lock = lock(git_path);
+    pull(git_path, index_path)
+}
+
+/// Pulls or clones the index and points its `config.json` at this cacher.
+fn sync(git_path: &PathBuf, index_path: &str, extern_url: &str) -> bool {
     debug!(
         "Syncing git repo at {} with {}, setting API url to {}",
         git_path.to_str().unwrap(),
         index_path,
         extern_url
     );
+    let pulled = pull(git_path, index_path);
+    write_config(git_path, extern_url);
+    pulled
+}
+
+fn pull(git_path: &PathBuf, index_path: &str) -> bool {
     let mut repo_path = git_path.clone();
     repo_path.push(".git");
     debug!("Repo path is {:?}", repo_path);
     let status = if repo_path.exists() {
-        match Command::new("git")
+        match git()
             .arg("pull")
             .arg("-q")
             .arg("--rebase")
@@ -41,22 +125,22 @@ pub fn git_sync(git_path: &PathBuf, index_path: &str, extern_url: &str) {
             Ok(s) => Some(s),
             Err(e) => {
                 warn!("Error pulling: {:?}", e);
-                return;
+                return false;
             }
         }
     } else {
-        match Command::new("git")
+        match git()
             .arg("clone")
             .arg("-qq")
             .arg(index_path)
-            .arg(&git_path)
+            .arg(git_path)
             .current_dir(git_path)
             .stderr(Stdio::null())
             .stdout(Stdio::null())
             .status()
         {
             Ok(s) => {
-                Command::new("git")
+                git()
                     .arg("config")
                     .arg("commit.gpgsign")
                     .arg("false")
@@ -65,7 +149,7 @@ pub fn git_sync(git_path: &PathBuf, index_path: &str, extern_url: &str) {
                     .status()
                     .unwrap();
                 // Set git user's name
-                Command::new("git")
+                git()
                     .arg("config")
                     .arg("user.name")
                     .arg("Cargo Cacher")
@@ -74,7 +158,7 @@ pub fn git_sync(git_path: &PathBuf, index_path: &str, extern_url: &str) {
                     .status()
                     .unwrap();
                 //set git user's email
-                Command::new("git")
+                git()
                     .arg("config")
                     .arg("user.email")
                     .arg("cargo-cacher@localhost")
@@ -84,26 +168,32 @@ pub fn git_sync(git_path: &PathBuf, index_path: &str, extern_url: &str) {
                     .unwrap();
                 Some(s)
             }
-            Err(_) => return,
+            Err(_) => return false,
         }
     };
+    if let Some(status) = status {
+        if status.success() {
+            trace!("Successfully synced");
+            return true;
+        } else {
+            warn!("Command was not a success");
+        }
+    }
+    warn!("Failed to update index");
+    false
+}
+
+fn write_config(git_path: &PathBuf, extern_url: &str) {
     let mut config_path = git_path.clone();
     config_path.push("config.json");
     if let Ok(mut f) = File::create(config_path) {
-        let config = format!(
-            "{{
-  \"dl\": \"{0}/api/v1/crates\",
-  \"api\": \"{0}/\"
-}}
-",
-            extern_url
-        );
-        let _ = f.write(&config.as_bytes());
-        Command::new("git")
+        let _ = f.write(registry_config(extern_url).as_bytes());
+        git()
             .arg("commit")
             .arg("-q")
             .arg("-a")
-            .arg("-m 'Updating config.json'")
+            .arg("-m")
+            .arg("Updating config.json")
             .arg("--no-gpg-sign")
             .stdout(Stdio::null())
             .current_dir(git_path)
@@ -116,13 +206,4 @@ pub fn git_sync(git_path: &PathBuf, index_path: &str, extern_url: &str) {
     } else {
         warn!("\tHad a problem modifying the config.json")
     }
-    if let Some(status) = status {
-        if status.success() {
-            trace!("Successfully synced");
-            return;
-        } else {
-            warn!("Command was not a success");
-        }
-    }
-    warn!("Failed to update index");
 }
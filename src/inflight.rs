@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+
+use crates::FetchError;
+
+type Key = (String, String);
+
+/// Tracks the crate downloads currently in progress so that concurrent
+/// misses on the same crate version share a single upstream fetch.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    fetches: Mutex<HashMap<Key, Arc<Flight>>>,
+}
+
+/// A download in progress that other requests can wait on.
+#[derive(Debug, Default)]
+pub struct Flight {
+    result: Mutex<Option<Result<(), FetchError>>>,
+    done: Condvar,
+}
+
+pub enum Claim {
+    /// Nobody else is fetching this crate; the caller must do it and report
+    /// back through the ticket.
+    Leader(Ticket),
+    /// Another request is already fetching this crate.
+    Follower(Arc<Flight>),
+}
+
+/// Held by the request responsible for a download. Waiters are released when
+/// it is finished, or dropped without finishing.
+pub struct Ticket {
+    in_flight: Arc<InFlight>,
+    key: Key,
+    flight: Arc<Flight>,
+    finished: bool,
+}
+
+impl InFlight {
+    pub fn claim(in_flight: &Arc<InFlight>, crate_name: &str, crate_version: &str) -> Claim {
+        let key = (crate_name.to_string(), crate_version.to_string());
+        let mut fetches = in_flight.fetches.lock().unwrap();
+        if let Some(flight) = fetches.get(&key) {
+            trace!("Waiting on in-flight fetch of {}:{}", crate_name, crate_version);
+            return Claim::Follower(flight.clone());
+        }
+        let flight = Arc::new(Flight::default());
+        fetches.insert(key.clone(), flight.clone());
+        Claim::Leader(Ticket {
+            in_flight: in_flight.clone(),
+            key,
+            flight,
+            finished: false,
+        })
+    }
+}
+
+impl Flight {
+    /// Blocks until the leading request has finished the download.
+    pub fn wait(&self) -> Result<(), FetchError> {
+        let mut result = self.result.lock().unwrap();
+        while result.is_none() {
+            result = self.done.wait(result).unwrap();
+        }
+        result.clone().unwrap()
+    }
+}
+
+impl Ticket {
+    pub fn finish(mut self, result: &Result<(), FetchError>) {
+        self.complete(result.clone());
+    }
+
+    fn complete(&mut self, result: Result<(), FetchError>) {
+        self.finished = true;
+        // Stop handing out this flight before releasing its waiters, so
+        // later requests look at the cache instead
+        self.in_flight.fetches.lock().unwrap().remove(&self.key);
+        *self.flight.result.lock().unwrap() = Some(result);
+        self.flight.done.notify_all();
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.complete(Err(FetchError::Unavailable("download was abandoned".into())));
+        }
+    }
+}
@@ -1,49 +1,82 @@
 extern crate cargo_lock;
 #[macro_use]
 extern crate clap;
+extern crate hyper;
 extern crate iron;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate logger;
 #[macro_use]
 extern crate router;
+extern crate r2d2;
+extern crate r2d2_sqlite;
 extern crate rusqlite;
+extern crate rustls;
 extern crate scoped_threadpool;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate sha2;
+extern crate toml;
+extern crate signal_hook;
 extern crate simple_logger;
+extern crate ureq;
 extern crate walkdir;
 extern crate humantime;
 
+use std::collections::HashMap;
 use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
 use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
-use std::sync::mpsc::SyncSender;
-use std::sync::Mutex;
-use std::time::Duration;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
+mod config_file;
 mod crates;
+mod evict;
 mod git;
 mod index_sync;
+mod inflight;
+mod listen;
+mod metrics;
+mod migrations;
+mod shutdown;
+mod sparse;
 mod stats;
+mod tls;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 // Iron Stuff
 use iron::prelude::*;
 use iron::status;
-use iron::AfterMiddleware;
+use iron::headers::{ContentLength, UserAgent};
+use iron::response::WriteBody;
+use hyper::header::Connection;
+use hyper::net::{HttpListener, HttpsListener};
+use iron::{AfterMiddleware, Handler, Listening, Protocol};
 use logger::Logger;
 use router::Router;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
-use iron::mime::{Mime, SubLevel, TopLevel};
+use iron::mime::{Attr, Mime, SubLevel, TopLevel, Value};
 
-use crates::{fetch, pre_fetch, size};
-use stats::Database;
+use config_file::FileConfig;
+use crates::{pre_fetch, Download, FetchError, Upstream};
+use inflight::{Claim, Ticket};
+use shutdown::Gate;
+use listen::{ListenAddr, UnixSocketListener};
+use stats::{Collector, Database, ExportFormat, Window};
+use tls::TlsServer;
 
 #[derive(Clone, Debug)]
 pub struct Config {
@@ -52,13 +85,51 @@ pub struct Config {
     index_path: String,
     crate_path: String,
     git_index_path: String,
+    stats_db: String,
+    stats_retention: Duration,
     upstream: String,
     index: String,
     extern_url: String,
     port: u16,
+    /// Addresses to serve on, every interface on `port` if empty
+    listen: Vec<ListenAddr>,
+    /// PEM certificate chain and private key to serve HTTPS with
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
     refresh_interval: Duration,
+    connect_timeout: Duration,
+    read_timeout: Duration,
     threads: u32,
+    max_cache_size: Option<u64>,
+    eviction_interval: Duration,
+    /// How long to wait for downloads to finish when shutting down
+    shutdown_timeout: Duration,
+    pinned: Vec<String>,
     log_level: log::Level,
+    command: Command,
+}
+
+/// What to do once configured.
+#[derive(Clone, Debug)]
+pub enum Command {
+    /// Sync the index and serve crates over HTTP
+    Serve,
+    /// Fetch the `--prefetch` file or `--all` crates, pulling the index first
+    /// with `sync`
+    Prefetch { sync: bool },
+    /// Remove partial downloads, evict down to `--max-cache-size` and roll
+    /// up old statistics
+    Gc,
+    /// Check cached crates against the index's checksums
+    Verify { delete: bool },
+    /// Print a summary of the statistics
+    Stats { window: Window, json: bool },
+    /// Write the download history to stdout
+    Export {
+        format: ExportFormat,
+        from: i64,
+        to: i64,
+    },
 }
 
 impl Config {
@@ -78,16 +149,30 @@ impl Config {
 
     [source.mirror]
     registry = "http://localhost:8080/index"
-    `"#,
+    `
+
+    or, to use the sparse protocol, `registry = "sparse+http://localhost:8080/sparse/"`"#,
+            )
+            .arg(
+                Arg::with_name("config")
+                    .global(true)
+                    .env("CARGO_CACHER_CONFIG")
+                    .long("config")
+                    .short("c")
+                    .takes_value(true)
+                    .help("TOML file to read settings from, which flags override"),
             )
             .arg(
                 Arg::with_name("debug")
+                    .global(true)
                     .short("d")
                     .multiple(true)
-                    .help("Sets the level of debugging information"),
+                    .help("Sets the level of debugging information [env: CARGO_CACHER_LOG_LEVEL]"),
             )
             .arg(
                 Arg::with_name("git")
+                    .global(true)
+                    .env("CARGO_CACHER_INDEX")
                     .short("g")
                     .required(false)
                     .takes_value(true)
@@ -98,14 +183,35 @@ impl Config {
             )
             .arg(
                 Arg::with_name("index")
+                    .global(true)
+                    .env("CARGO_CACHER_INDEX_PATH")
                     .long("index")
                     .short("i")
                     .required(false)
                     .takes_value(true)
                     .help("Path to store the indexes (git and crates) at (Default: $HOME/.crates)"),
             )
+            .arg(
+                Arg::with_name("stats-db")
+                    .global(true)
+                    .env("CARGO_CACHER_STATS_DB")
+                    .long("stats-db")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Path of the statistics database (Default: $index/stats.sqlite)"),
+            )
+            .arg(
+                Arg::with_name("stats-retention")
+                    .global(true)
+                    .env("CARGO_CACHER_STATS_RETENTION")
+                    .long("stats-retention")
+                    .takes_value(true)
+                    .help("How long to keep each download before rolling it up into daily totals (Default: 90 days)"),
+            )
             .arg(
                 Arg::with_name("upstream")
+                    .global(true)
+                    .env("CARGO_CACHER_UPSTREAM")
                     .long("upstream")
                     .short("u")
                     .required(false)
@@ -114,29 +220,82 @@ impl Config {
             )
             .arg(
                 Arg::with_name("port")
+                    .global(true)
+                    .env("CARGO_CACHER_PORT")
                     .long("port")
                     .short("p")
                     .required(false)
                     .takes_value(true)
                     .help("Port to listen on (Default: 8080)"),
             )
+            .arg(
+                Arg::with_name("listen")
+                    .global(true)
+                    .long("listen")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Address to listen on, as ip:port or unix:/path, instead of every interface on --port [env: CARGO_CACHER_LISTEN, comma separated]"),
+            )
             .arg(
                 Arg::with_name("extern-url")
+                    .global(true)
+                    .env("CARGO_CACHER_EXTERN_URL")
                     .long("eurl")
                     .short("e")
                     .required(false)
                     .takes_value(true)
-                    .help("Externally reachable URL (Default: http://localhost:8080)")
+                    .help("Externally reachable URL (Default: the first --listen address, or http://localhost:<port>)")
+            )
+            .arg(
+                Arg::with_name("tls-cert")
+                    .global(true)
+                    .env("CARGO_CACHER_TLS_CERT")
+                    .long("tls-cert")
+                    .takes_value(true)
+                    .requires("tls-key")
+                    .help("PEM certificate chain to serve HTTPS with, reloaded on SIGHUP"),
+            )
+            .arg(
+                Arg::with_name("tls-key")
+                    .global(true)
+                    .env("CARGO_CACHER_TLS_KEY")
+                    .long("tls-key")
+                    .takes_value(true)
+                    .requires("tls-cert")
+                    .help("PEM private key for --tls-cert"),
             )
             .arg(
                 Arg::with_name("refresh")
+                    .global(true)
+                    .env("CARGO_CACHER_REFRESH_INTERVAL")
                     .short("r")
                     .required(false)
                     .takes_value(true)
                     .help("Refresh interval for the git index (Default: 10 minutes)"),
             )
+            .arg(
+                Arg::with_name("connect-timeout")
+                    .global(true)
+                    .env("CARGO_CACHER_CONNECT_TIMEOUT")
+                    .long("connect-timeout")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Timeout for connecting to the upstream crate source (Default: 10 seconds)"),
+            )
+            .arg(
+                Arg::with_name("read-timeout")
+                    .global(true)
+                    .env("CARGO_CACHER_READ_TIMEOUT")
+                    .long("read-timeout")
+                    .required(false)
+                    .takes_value(true)
+                    .help("Timeout for reads from the upstream crate source (Default: 30 seconds)"),
+            )
             .arg(
                 Arg::with_name("prefetch")
+                    .global(true)
+                    .env("CARGO_CACHER_PREFETCH_PATH")
                     .short("f")
                     .takes_value(true)
                     .required(false)
@@ -144,71 +303,370 @@ impl Config {
             )
             .arg(
                 Arg::with_name("threads")
+                    .global(true)
+                    .env("CARGO_CACHER_THREADS")
                     .short("t")
                     .help("How many threads to use to fetch crates in the background (Default: 16)")
                     .takes_value(true),
             )
             .arg(
                 Arg::with_name("all")
+                    .global(true)
                     .long("all")
                     .short("a")
-                    .help("Prefetch entire Cargo index"),
+                    .help("Prefetch entire Cargo index [env: CARGO_CACHER_ALL]"),
+            )
+            .arg(
+                Arg::with_name("max-cache-size")
+                    .global(true)
+                    .env("CARGO_CACHER_MAX_CACHE_SIZE")
+                    .long("max-cache-size")
+                    .takes_value(true)
+                    .help("Evict the least recently used crates beyond this size, ex: 20G (Default: unlimited)"),
+            )
+            .arg(
+                Arg::with_name("eviction-interval")
+                    .global(true)
+                    .env("CARGO_CACHER_EVICTION_INTERVAL")
+                    .long("eviction-interval")
+                    .takes_value(true)
+                    .help("How often to measure the cache size and evict crates (Default: 10 minutes)"),
+            )
+            .arg(
+                Arg::with_name("shutdown-timeout")
+                    .global(true)
+                    .env("CARGO_CACHER_SHUTDOWN_TIMEOUT")
+                    .long("shutdown-timeout")
+                    .takes_value(true)
+                    .help("How long to wait for downloads to finish on SIGINT or SIGTERM (Default: 60 seconds)"),
+            )
+            .arg(
+                Arg::with_name("pin")
+                    .global(true)
+                    .long("pin")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Never evict this crate, as crate_name or crate_name=version [env: CARGO_CACHER_PINNED, comma separated]"),
+            )
+            .subcommand(
+                SubCommand::with_name("serve")
+                    .about("Syncs the index and serves crates over HTTP (the default)"),
+            )
+            .subcommand(
+                SubCommand::with_name("prefetch")
+                    .about("Fetches crates into the cache, then exits")
+                    .arg(
+                        Arg::with_name("sync")
+                            .long("sync")
+                            .help("Pull the index first, for when no server keeps it up to date"),
+                    )
+                    .arg(
+                        Arg::with_name("FILE")
+                            .help("Path with a list of crate_name=version OR lock file to fetch (Default: --prefetch)"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("gc")
+                    .about("Removes partial downloads, evicts crates beyond --max-cache-size and rolls up old statistics"),
+            )
+            .subcommand(
+                SubCommand::with_name("verify")
+                    .about("Checks cached crates against the checksums in the local index")
+                    .arg(
+                        Arg::with_name("delete")
+                            .long("delete")
+                            .help("Remove crates that fail the check"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("stats")
+                    .about("Prints a summary of the download statistics")
+                    .args(&time_range_args())
+                    .arg(
+                        Arg::with_name("bucket")
+                            .long("bucket")
+                            .takes_value(true)
+                            .possible_values(&["hour", "day"])
+                            .help("Size of each step of the time series with --json (Default: hour)"),
+                    )
+                    .arg(
+                        Arg::with_name("json")
+                            .long("json")
+                            .help("Print the same JSON as /stats.json"),
+                    ),
+            )
+            .subcommand(
+                SubCommand::with_name("export")
+                    .about("Writes the download history to stdout")
+                    .arg(
+                        Arg::with_name("format")
+                            .long("format")
+                            .takes_value(true)
+                            .possible_values(&["csv", "ndjson"])
+                            .help("Output format (Default: csv)"),
+                    )
+                    .args(&time_range_args()),
             )
             .get_matches();
 
-        let log_level = match matches.occurrences_of("debug") {
-            0 => log::Level::Warn,
-            1 => log::Level::Info,
-            2 => log::Level::Debug,
-            3 | _ => log::Level::Trace,
+        // Flags win over environment variables, then the config file, then
+        // the defaults. Clap reads the environment for options with values.
+        let file = match matches.value_of("config") {
+            Some(path) => match FileConfig::load(path) {
+                Ok(file) => file,
+                Err(e) => {
+                    eprintln!("Couldn't read the config file {}: {}", path, e);
+                    exit(-1);
+                }
+            },
+            None => FileConfig::default(),
+        };
+        let value = |name: &str| -> Option<String> {
+            matches.value_of(name).map(Into::into).or_else(|| file.value(name))
+        };
+
+        let env_log_level = env::var("CARGO_CACHER_LOG_LEVEL").ok();
+        let named_level = env_log_level.as_deref().or(file.log_level());
+        let log_level = match (matches.occurrences_of("debug"), named_level) {
+            (0, Some(level)) => match log::Level::from_str(level) {
+                Ok(level) => level,
+                Err(_) => {
+                    eprintln!("Error while parsing log_level: unknown level \"{}\".", level);
+                    eprintln!("Try one of \"error\", \"warn\", \"info\", \"debug\" or \"trace\".");
+                    exit(-1);
+                }
+            },
+            (0, None) => log::Level::Warn,
+            (1, _) => log::Level::Info,
+            (2, _) => log::Level::Debug,
+            _ => log::Level::Trace,
         };
         let default_crate_path = format!("{}/.crates", dirs::home_dir().unwrap().to_str().unwrap());
-        let index_path: String = matches
-            .value_of("index")
-            .unwrap_or(&default_crate_path)
-            .into();
+        let index_path: String = value("index").unwrap_or(default_crate_path);
 
         let mut crate_path = index_path.clone();
         crate_path.push_str("/crates");
         let mut git_index: String = index_path.clone();
         git_index.push_str("/index");
-        let port = u16::from_str(matches.value_of("port")
-                    .unwrap_or("8080"))
-                .unwrap_or(8080);
-        let refresh_interval_human = matches.value_of("refresh")
-            .unwrap_or("10 minutes")
-            .parse::<humantime::Duration>();
-        let refresh_interval_seconds = u64::from_str(matches.value_of("refresh").unwrap_or("600"));
-        let refresh_interval = match (refresh_interval_human, refresh_interval_seconds) {
-            (Ok(d), _) => d.into(),
-            (_, Ok(s)) => Duration::new(s, 0),
-            (Err(e), _) => {
-                eprintln!("Error while parsing refresh interval: {}.", e);
-                eprintln!("Try values like \"600s\" or \"2 hours\".");
+        let stats_db = value("stats-db").unwrap_or(format!("{}/stats.sqlite", index_path));
+        let port = value("port")
+            .and_then(|p| u16::from_str(&p).ok())
+            .unwrap_or(8080);
+        let refresh_interval = duration_arg("refresh", value("refresh"), "10 minutes");
+        let (tls_cert, tls_key) = match (value("tls-cert"), value("tls-key")) {
+            (Some(cert), Some(key)) => (Some(cert), Some(key)),
+            (None, None) => (None, None),
+            _ => {
+                eprintln!("Error: --tls-cert and --tls-key must be given together.");
                 exit(-1);
             }
         };
+        let scheme = if tls_cert.is_some() { "https" } else { "http" };
+        let command = match matches.subcommand() {
+            ("prefetch", Some(matches)) => Command::Prefetch {
+                sync: matches.is_present("sync"),
+            },
+            ("gc", _) => Command::Gc,
+            ("verify", Some(matches)) => Command::Verify {
+                delete: matches.is_present("delete"),
+            },
+            ("stats", Some(matches)) => stats_command(matches),
+            ("export", Some(matches)) => export_command(matches),
+            _ => Command::Serve,
+        };
+        let prefetch = matches.subcommand_matches("prefetch");
+        let listen: Vec<ListenAddr> = matches
+            .values_of("listen")
+            .map(|addrs| addrs.map(Into::into).collect())
+            .or_else(|| env_list("CARGO_CACHER_LISTEN"))
+            .unwrap_or_else(|| file.listen())
+            .iter()
+            .map(|addr| listen_arg(addr))
+            .collect();
+        let extern_url = match value("extern-url").or_else(|| default_extern_url(scheme, port, &listen)) {
+            Some(url) => url,
+            None if matches!(command, Command::Serve) => {
+                eprintln!("Error: -e is needed to tell cargo where to download crates when only listening on Unix sockets.");
+                exit(-1);
+            }
+            None => format!("{}://localhost:{}", scheme, port),
+        };
         Config {
-            all: matches.is_present("all"),
-            prefetch_path: matches.value_of("prefetch").map(|r| r.to_string()),
-            index_path: index_path,
-            crate_path: crate_path,
-            git_index_path: git_index,
-            upstream: matches
-                .value_of("upstream")
-                .unwrap_or("https://static.crates.io/crates/")
-                .into(),
-            index: matches
-                .value_of("git")
-                .unwrap_or("https://github.com/rust-lang/crates.io-index.git")
-                .into(),
-            port: u16::from_str(matches.value_of("port").unwrap_or("8080")).unwrap_or(8080),
-            extern_url: matches.value_of("extern-url")
+            all: matches.is_present("all")
+                || env_bool("CARGO_CACHER_ALL").unwrap_or(file.all()),
+            prefetch_path: prefetch
+                .and_then(|m| m.value_of("FILE"))
                 .map(Into::into)
-                .unwrap_or(format!("http://localhost:{}", port)),
-            refresh_interval: refresh_interval,
-            threads: u32::from_str(matches.value_of("threads").unwrap_or("16")).unwrap_or(16),
-            log_level: log_level,
+                .or_else(|| value("prefetch")),
+            index_path,
+            crate_path,
+            git_index_path: git_index,
+            stats_db,
+            stats_retention: duration_arg("stats-retention", value("stats-retention"), "90 days"),
+            upstream: value("upstream").unwrap_or("https://static.crates.io/crates/".into()),
+            index: value("git").unwrap_or("https://github.com/rust-lang/crates.io-index.git".into()),
+            port,
+            listen,
+            extern_url,
+            tls_cert,
+            tls_key,
+            refresh_interval,
+            connect_timeout: duration_arg("connect-timeout", value("connect-timeout"), "10 seconds"),
+            read_timeout: duration_arg("read-timeout", value("read-timeout"), "30 seconds"),
+            threads: value("threads")
+                .and_then(|t| u32::from_str(&t).ok())
+                .unwrap_or(16),
+            max_cache_size: value("max-cache-size").map(|s| size_arg("max-cache-size", &s)),
+            eviction_interval: duration_arg("eviction-interval", value("eviction-interval"), "10 minutes"),
+            shutdown_timeout: duration_arg("shutdown-timeout", value("shutdown-timeout"), "60 seconds"),
+            pinned: matches
+                .values_of("pin")
+                .map(|pins| pins.map(Into::into).collect())
+                .or_else(|| env_list("CARGO_CACHER_PINNED"))
+                .unwrap_or_else(|| file.pinned()),
+            log_level,
+            command,
+        }
+    }
+}
+
+/// `--from` and `--to`, read the same way as the `/stats` query parameters.
+fn time_range_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::with_name("from")
+            .long("from")
+            .takes_value(true)
+            .help("Start of the time range, as seconds since the epoch, an RFC 3339 date or a duration before now (Default: 24 hours ago)"),
+        Arg::with_name("to")
+            .long("to")
+            .takes_value(true)
+            .help("End of the time range, like --from (Default: now)"),
+    ]
+}
+
+/// The subcommand's arguments as if they were `/stats` query parameters.
+fn query_args(matches: &ArgMatches, names: &[&str]) -> HashMap<String, String> {
+    names
+        .iter()
+        .filter_map(|&name| matches.value_of(name).map(|v| (name.to_string(), v.to_string())))
+        .collect()
+}
+
+fn stats_command(matches: &ArgMatches) -> Command {
+    match Window::from_query(&query_args(matches, &["from", "to", "bucket"])) {
+        Ok(window) => Command::Stats {
+            window,
+            json: matches.is_present("json"),
+        },
+        Err(e) => {
+            eprintln!("Error while parsing the time range: {}.", e);
+            exit(-1);
+        }
+    }
+}
+
+fn export_command(matches: &ArgMatches) -> Command {
+    let format = ExportFormat::from_str(matches.value_of("format").unwrap_or("csv")).unwrap();
+    match stats::time_range(&query_args(matches, &["from", "to"])) {
+        Ok((from, to)) => Command::Export { format, from, to },
+        Err(e) => {
+            eprintln!("Error while parsing the time range: {}.", e);
+            exit(-1);
+        }
+    }
+}
+
+/// Reads a boolean environment variable, if it is set.
+fn env_bool(name: &str) -> Option<bool> {
+    let value = env::var(name).ok()?;
+    match value.to_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" | "" => Some(false),
+        _ => {
+            eprintln!("Error while parsing {}: expected true or false, got \"{}\".", name, value);
+            exit(-1);
+        }
+    }
+}
+
+/// Reads a comma separated environment variable, if it is set.
+fn env_list(name: &str) -> Option<Vec<String>> {
+    let value = env::var(name).ok()?;
+    Some(
+        value
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect(),
+    )
+}
+
+/// Where cargo reaches the server when `-e` isn't given: the first TCP
+/// `--listen` address, or `--port` on localhost without any. None if only
+/// listening on Unix sockets.
+fn default_extern_url(scheme: &str, port: u16, listen: &[ListenAddr]) -> Option<String> {
+    if listen.is_empty() {
+        return Some(format!("{}://localhost:{}", scheme, port));
+    }
+    listen.iter().find_map(|addr| match *addr {
+        ListenAddr::Tcp(addr) if addr.ip().is_unspecified() => {
+            Some(format!("{}://localhost:{}", scheme, addr.port()))
+        }
+        ListenAddr::Tcp(addr) => Some(format!("{}://{}", scheme, addr)),
+        ListenAddr::Unix(_) => None,
+    })
+}
+
+fn listen_arg(value: &str) -> ListenAddr {
+    match ListenAddr::from_str(value) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Error while parsing listen: {}.", e);
+            eprintln!("Try values like \"127.0.0.1:8080\", \"[::1]:8080\" or \"unix:/run/cargo-cacher.sock\".");
+            exit(-1);
+        }
+    }
+}
+
+/// Parses a duration given either in a human readable form ("2 hours") or as
+/// a plain number of seconds.
+fn duration_arg(name: &str, value: Option<String>, default: &str) -> Duration {
+    let value = value.as_deref().unwrap_or(default);
+    let human = value.parse::<humantime::Duration>();
+    let seconds = u64::from_str(value);
+    match (human, seconds) {
+        (Ok(d), _) => d.into(),
+        (_, Ok(s)) => Duration::new(s, 0),
+        (Err(e), _) => {
+            eprintln!("Error while parsing {}: {}.", name, e);
+            eprintln!("Try values like \"600s\" or \"2 hours\".");
+            exit(-1);
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional binary unit, ex: "512M" or "20GiB".
+fn size_arg(name: &str, value: &str) -> u64 {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let multiplier: u64 = match unit.trim().to_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => 0,
+    };
+    match u64::from_str(number) {
+        Ok(n) if multiplier > 0 => n * multiplier,
+        _ => {
+            eprintln!("Error while parsing {}: invalid size \"{}\".", name, value);
+            eprintln!("Try values like \"500M\" or \"20G\".");
+            exit(-1);
         }
     }
 }
@@ -223,6 +681,16 @@ pub struct CargoRequest {
     hit: bool,
     /// Filesize in bytes
     size: i64,
+    /// Seconds since the epoch
+    time: i64,
+    /// Milliseconds from receiving the request to sending the last byte
+    duration_ms: i64,
+    /// Milliseconds spent waiting on upstream, for cache misses
+    upstream_ms: Option<i64>,
+    /// IP address of the client
+    remote_addr: Option<String>,
+    /// Client's `User-Agent` header, which for cargo includes its version
+    user_agent: Option<String>,
 }
 
 fn main() {
@@ -231,18 +699,257 @@ fn main() {
     simple_logger::init_with_level(config.log_level).unwrap();
     info!("Configuration: {:?}", config);
 
-    setup_filesystem(&config);
+    match config.command {
+        Command::Serve => serve(&config),
+        Command::Prefetch { sync } => prefetch(&config, sync),
+        Command::Gc => gc(&config),
+        Command::Verify { delete } => verify(&config, delete),
+        Command::Stats { window, json } => print_stats(&config, &window, json),
+        Command::Export { format, from, to } => export(&config, format, from, to),
+    }
+}
+
+fn open_database(config: &Config) -> Database {
+    match Database::open(&config.stats_db) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Couldn't open the stats database at {}: {}", config.stats_db, e);
+            exit(-1);
+        }
+    }
+}
+
+fn serve(config: &Config) {
+    setup_filesystem(config);
+    crates::remove_partial_downloads(&config.crate_path);
+    let db = open_database(config);
+
+    let upstream = Upstream::new(config);
+    let collector = stats::stat_collector(&db);
+    // Before the first sync, which can take minutes, so a signal during it
+    // waits for git rather than leaving it running on its own
+    shut_down_on_signal(&collector, config.shutdown_timeout);
+
+    index_sync::init_sync(PathBuf::from(&config.git_index_path), config, &upstream);
+
+    pre_fetch(config, &upstream);
+    stats::init_rollups(&db, config.stats_retention);
+    evict::init_eviction(config, &db);
+    let tls = tls_server(config);
+    server(config, &upstream, &db, collector, tls.as_ref())
+}
+
+/// Fetches crates into the cache without serving them. Exits with an error
+/// if any couldn't be fetched.
+fn prefetch(config: &Config, sync: bool) {
+    if !config.all && config.prefetch_path.is_none() {
+        eprintln!("Nothing to prefetch, give a file of crates to fetch or --all.");
+        exit(-1);
+    }
+    setup_filesystem(config);
+    // The server owns the index: it keeps it synced and points its
+    // `config.json` at itself, so only pull when asked to.
+    let git_index_path = PathBuf::from(&config.git_index_path);
+    if sync && !index_sync::update(&git_index_path, &config.index) {
+        eprintln!("Couldn't sync the index from {}", config.index);
+        exit(-1);
+    }
+    if !git_index_path.join("config.json").exists() {
+        eprintln!(
+            "No index at {}, run the server or pass --sync first.",
+            git_index_path.display()
+        );
+        exit(-1);
+    }
+
+    let upstream = Upstream::new(config);
+    let mut failed = 0;
+    if config.all {
+        failed += crates::fetch_index(config, &upstream);
+    }
+    if let Some(ref prefetch_path) = config.prefetch_path {
+        match crates::prefetch_file(config, &upstream, prefetch_path) {
+            Ok(n) => failed += n,
+            Err(e) => {
+                eprintln!("{}", e);
+                exit(-1);
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!("Couldn't fetch {} crates", failed);
+        exit(1);
+    }
+}
+
+/// Cleans up the cache and the stats database, like the server does in the
+/// background.
+fn gc(config: &Config) {
+    crates::remove_partial_downloads(&config.crate_path);
+    let db = open_database(config);
+
+    let before = evict::cache_size(&config.crate_path);
+    match config.max_cache_size {
+        Some(max_size) => {
+            evict::evict(config, &db);
+            let after = evict::cache_size(&config.crate_path);
+            println!(
+                "Cache is using {} of {} bytes, freed {} bytes",
+                after,
+                max_size,
+                before.saturating_sub(after)
+            );
+        }
+        None => println!("Cache is using {} bytes, set --max-cache-size to evict crates", before),
+    }
+
+    match db.roll_up(stats::rollup_cutoff(config.stats_retention)) {
+        Ok(n) => println!("Rolled up {} downloads", n),
+        Err(e) => {
+            eprintln!("Couldn't roll up old downloads: {}", e);
+            exit(-1);
+        }
+    }
+}
+
+/// Checks every cached crate against the local index, which is only as
+/// fresh as the last sync. Exits with an error if any crate fails.
+fn verify(config: &Config, delete: bool) {
+    let mut checked = 0;
+    let mut failed = 0;
+    for c in evict::cached_crates(&config.crate_path) {
+        checked += 1;
+        match crates::verify(&c.path, &config.git_index_path, &c.name, &c.version) {
+            Ok(()) => trace!("{}:{} is valid", c.name, c.version),
+            Err(FetchError::NotInIndex) => {
+                println!("{} {}: not in the index, skipping", c.name, c.version)
+            }
+            Err(e) => {
+                failed += 1;
+                println!("{} {}: {}", c.name, c.version, e);
+                if delete {
+                    match c.remove() {
+                        Ok(_) => println!("{} {}: removed", c.name, c.version),
+                        Err(e) => eprintln!("Couldn't remove {:?}: {}", c.path, e),
+                    }
+                }
+            }
+        }
+    }
+    println!("Checked {} crates, {} failed", checked, failed);
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+fn print_stats(config: &Config, window: &Window, json: bool) {
+    let db = open_database(config);
+    let stats = db.stats(window);
+    if json {
+        println!("{}", stats.as_json());
+        return;
+    }
+
+    let time = |t: i64| humantime::format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs(t as u64));
+    let percent = |part: i64| match stats.downloads {
+        0 => "-".to_string(),
+        total => format!("{:.1}%", 100.0 * part as f64 / total as f64),
+    };
+    let ms = |value: Option<i64>| value.map(|v| format!("{} ms", v)).unwrap_or_else(|| "-".into());
+    let latency = |p: &stats::Percentiles| {
+        format!("p50 {}, p95 {}, p99 {}", ms(p.p50), ms(p.p95), ms(p.p99))
+    };
 
-    index_sync::init_sync(PathBuf::from(&config.git_index_path), &config);
+    println!("From {} to {}", time(window.from), time(window.to));
+    println!("Downloads:        {}", stats.downloads);
+    println!("Cache hits:       {} ({})", stats.hits, percent(stats.hits));
+    println!("Cache misses:     {} ({})", stats.misses, percent(stats.misses));
+    println!("Bandwidth saved:  {} bytes", stats.bandwidth_saved);
+    println!("Cache size:       {} bytes", evict::cache_size(&config.crate_path));
+    println!("Hit latency:      {}", latency(&stats.latency.hit));
+    println!("Miss latency:     {}", latency(&stats.latency.miss));
+    println!("Upstream latency: {}", latency(&stats.latency.upstream));
 
-    pre_fetch(&config);
-    let collector = stats::stat_collector();
-    server(&config, collector)
+    let top = db.top_crates(window.from, window.to, 10, false);
+    if !top.is_empty() {
+        println!("Most requested:");
+        for c in top {
+            println!("  {:<30} {:>8} downloads, {} hits", c.name, c.downloads, c.hits);
+        }
+    }
+}
+
+fn export(config: &Config, format: ExportFormat, from: i64, to: i64) {
+    let db = open_database(config);
+    let stdout = io::stdout();
+    if let Err(e) = db.export(from, to, format, &mut stdout.lock()) {
+        eprintln!("Couldn't export the download history: {}", e);
+        exit(-1);
+    }
+}
+
+/// Shuts down gracefully on SIGINT or SIGTERM, see `shutdown::shut_down`.
+/// A second signal exits straight away.
+fn shut_down_on_signal(collector: &Collector, timeout: Duration) {
+    let mut signals = match Signals::new([SIGINT, SIGTERM]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Couldn't listen for signals: {}", e);
+            return;
+        }
+    };
+    let collector = collector.clone();
+    thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("Received signal {}, shutting down", signal);
+            thread::spawn(move || {
+                shutdown::shut_down(&collector, timeout);
+                exit(0);
+            });
+        }
+        if let Some(signal) = signals.next() {
+            warn!("Received signal {} again, exiting now", signal);
+            exit(1);
+        }
+    });
+}
+
+/// Loads the `--tls-cert` and `--tls-key`, if given, and reloads them on
+/// SIGHUP.
+fn tls_server(config: &Config) -> Option<TlsServer> {
+    let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return None,
+    };
+    let tls = match TlsServer::new(cert, key) {
+        Ok(tls) => tls,
+        Err(e) => {
+            eprintln!("Couldn't load the TLS certificate: {}", e);
+            exit(-1);
+        }
+    };
+    match Signals::new([SIGHUP]) {
+        Ok(mut signals) => {
+            let tls = tls.clone();
+            thread::spawn(move || {
+                for _ in signals.forever() {
+                    match tls.reload() {
+                        Ok(()) => info!("Reloaded the TLS certificate"),
+                        Err(e) => error!("Couldn't reload the TLS certificate: {}", e),
+                    }
+                }
+            });
+        }
+        Err(e) => warn!("Couldn't listen for SIGHUP, the TLS certificate won't be reloaded: {}", e),
+    }
+    Some(tls)
 }
 
 fn setup_filesystem(config: &Config) {
     let _ = std::fs::create_dir_all(&config.crate_path);
     let _ = std::fs::create_dir_all(&config.git_index_path);
+    metrics::CACHE_SIZE.set(evict::cache_size(&config.crate_path));
 }
 
 struct CorsMiddleware;
@@ -255,23 +962,57 @@ impl AfterMiddleware for CorsMiddleware {
     }
 }
 
-fn server(config: &Config, stats: SyncSender<CargoRequest>) {
+fn server(config: &Config, upstream: &Upstream, db: &Database, stats: Collector, tls: Option<&TlsServer>) {
     // web server to handle DL requests
-    let host = format!(":::{}", config.port);
     let router = router!(
+        metrics: get "/metrics" => |_request: &mut Request| metrics_view(),
         stats_json: get "/stats.json" => {
-                move |_request: &mut Request|
-                    stats_json()
+            let db = db.clone();
+            move |request: &mut Request|
+                stats_json(request, &db)
         },
-        stats: get "/stats" => {
+        stats: get "/stats" => |_request: &mut Request| stats_view(),
+        stats_status: get "/stats/status" => {
+            let config = config.clone();
             move |_request: &mut Request|
-                stats_view()
+                stats_status(&config)
+        },
+        stats_export: get "/stats/export" => {
+            let db = db.clone();
+            move |request: &mut Request|
+                stats_export(request, &db)
+        },
+        stats_misses: get "/stats/misses" => {
+            let db = db.clone();
+            move |request: &mut Request|
+                stats_misses(request, &db)
+        },
+        stats_crates: get "/stats/crates" => {
+            let db = db.clone();
+            move |request: &mut Request|
+                stats_crates(request, &db)
+        },
+        stats_crate: get "/stats/crates/:crate_name" => {
+            let db = db.clone();
+            move |request: &mut Request|
+                stats_crate(request, &db)
         },
         download: get "api/v1/crates/:crate_name/:crate_version/download" => {
             let config = config.clone();
-            let stats = Mutex::new(stats.clone());
+            let upstream = upstream.clone();
+            let stats = stats.clone();
+            move |request: &mut Request|
+                fetch_download(request, &config, &upstream, &stats)
+        },
+        sparse_config: get "sparse/config.json" => {
+            let config = config.clone();
+            move |_request: &mut Request|
+                sparse::config_json(&config)
+        },
+        sparse: get "sparse/*" => {
+            let config = config.clone();
             move |request: &mut Request|
-                fetch_download(request, &config, &stats)
+                sparse::sparse(request, &config)
         },
         head: get "index/*" => {
             let config = config.clone();
@@ -302,9 +1043,76 @@ fn server(config: &Config, stats: SyncSender<CargoRequest>) {
     chain.link_after(logger_after);
 
     chain.link_after(CorsMiddleware);
-    println!("Listening on {}", host);
-    // Iron::new(chain).http(host).unwrap();
-    Iron::new(chain).http(&host[..]).unwrap();
+    let chain = Arc::new(chain);
+
+    let mut servers = Vec::new();
+    if config.listen.is_empty() {
+        let any = ListenAddr::Tcp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, config.port)));
+        servers.push(match listen(&chain, &any, tls) {
+            Ok(listening) => listening,
+            Err(e) => {
+                // Hosts with IPv6 disabled
+                info!("Couldn't listen on {}: {}", any, e);
+                let any = ListenAddr::Tcp(SocketAddr::from((Ipv4Addr::UNSPECIFIED, config.port)));
+                listen_or_exit(&chain, &any, tls)
+            }
+        });
+    }
+    for addr in &config.listen {
+        servers.push(listen_or_exit(&chain, addr, tls));
+    }
+    // Dropping these waits for the servers, which is forever
+}
+
+/// Lets every listener share the one chain.
+struct SharedChain(Arc<Chain>);
+
+impl Handler for SharedChain {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let mut res = self.0.handle(req)?;
+        if shutdown::stopping() {
+            // Don't let clients keep the connection open for more requests
+            res.headers.set(Connection::close());
+        }
+        Ok(res)
+    }
+}
+
+/// Serves HTTPS on TCP addresses when given `tls`. Unix sockets are always
+/// plain HTTP, as they are for a reverse proxy on the same host.
+fn listen(chain: &Arc<Chain>, addr: &ListenAddr, tls: Option<&TlsServer>) -> hyper::Result<Listening> {
+    let iron = Iron::new(SharedChain(chain.clone()));
+    let (listening, protocol) = match *addr {
+        ListenAddr::Tcp(socket) => {
+            let listener = TcpListener::bind(socket)?;
+            shutdown::add_listener(listener.try_clone()?.into(), None);
+            let listener = HttpListener::from(listener);
+            match tls {
+                Some(tls) => {
+                    let listener = HttpsListener::with_listener(listener, tls.clone());
+                    (iron.listen(Gate(listener), Protocol::https())?, "HTTPS")
+                }
+                None => (iron.listen(Gate(listener), Protocol::http())?, "HTTP"),
+            }
+        }
+        ListenAddr::Unix(ref path) => {
+            let listener = UnixSocketListener::bind(path)?;
+            shutdown::add_listener(listener.try_clone()?.into(), Some(path.clone()));
+            (iron.listen(Gate(listener), Protocol::http())?, "HTTP")
+        }
+    };
+    println!("Listening on {} ({})", addr, protocol);
+    Ok(listening)
+}
+
+fn listen_or_exit(chain: &Arc<Chain>, addr: &ListenAddr, tls: Option<&TlsServer>) -> Listening {
+    match listen(chain, addr, tls) {
+        Ok(listening) => listening,
+        Err(e) => {
+            eprintln!("Couldn't listen on {}: {}", addr, e);
+            exit(-1);
+        }
+    }
 }
 
 pub fn log(req: &mut Request) -> IronResult<Response> {
@@ -315,16 +1123,17 @@ pub fn log(req: &mut Request) -> IronResult<Response> {
 fn fetch_download(
     req: &mut Request,
     config: &Config,
-    stats: &Mutex<SyncSender<CargoRequest>>,
+    upstream: &Upstream,
+    stats: &Collector,
 ) -> IronResult<Response> {
-    let stats = stats.lock().unwrap();
-    let ref crate_name = req
+    let started = Instant::now();
+    let crate_name = &req
         .extensions
         .get::<Router>()
         .unwrap()
         .find("crate_name")
         .unwrap();
-    let ref crate_version = req
+    let crate_version = &req
         .extensions
         .get::<Router>()
         .unwrap()
@@ -336,66 +1145,377 @@ fn fetch_download(
         "{}/crates/{}/{}",
         config.index_path, crate_name, crate_version
     ));
-    if path.exists() {
-        debug!("path {:?} exists!", path);
-        let _ = stats.send(CargoRequest {
-            name: crate_name.to_string(),
-            version: crate_version.to_string(),
-            hit: true,
-            size: size(&path) as i64,
-        });
-        Ok(Response::with((status::Ok, path)))
-    } else {
+    let recorder = Recorder {
+        stats: stats.clone(),
+        _busy: shutdown::busy(),
+        name: crate_name.to_string(),
+        version: crate_version.to_string(),
+        remote_addr: remote_ip(req),
+        user_agent: req.headers.get::<UserAgent>().map(|ua| ua.to_string()),
+        started,
+    };
+    // Open the crate rather than checking that it exists, so one evicted in
+    // between is fetched again instead of turning into a 404
+    let ticket = loop {
+        if let Ok(file) = File::open(&path) {
+            debug!("path {:?} exists!", path);
+            return Ok(serve_cached(file, recorder));
+        }
         debug!("path {:?} doesn't exist!", path);
 
-        match fetch(
-            &path,
-            &config.upstream,
-            &config.index_path,
-            &crate_name,
-            &crate_version,
-        ) {
-            Ok(_) => {
-                let _ = stats.send(CargoRequest {
-                    name: crate_name.to_string(),
-                    version: crate_version.to_string(),
-                    hit: false,
-                    size: size(&path) as i64,
-                });
-                Ok(Response::with((status::Ok, path)))
+        match upstream.claim(crate_name, crate_version) {
+            Claim::Leader(ticket) => break ticket,
+            // Only one request goes upstream, the rest are served from the cache
+            Claim::Follower(flight) => {
+                if let Err(e) = flight.wait() {
+                    return Ok(fetch_error_response(&e));
+                }
             }
-            Err(e) => {
-                error!("{:?}", e);
-                return Ok(Response::with((
-                    status::ServiceUnavailable,
-                    "Couldn't fetch from Crates.io",
-                )));
+        }
+    };
+    // The previous fetch may have finished since we last looked
+    if let Ok(file) = File::open(&path) {
+        ticket.finish(&Ok(()));
+        return Ok(serve_cached(file, recorder));
+    }
+    match crates::start(&path, config, upstream, crate_name, crate_version) {
+        Ok(download) => {
+            let len = download.len();
+            let body: Box<dyn WriteBody> = Box::new(StreamingDownload {
+                download: Some(download),
+                ticket: Some(ticket),
+                recorder,
+            });
+            let mut res = Response::with((status::Ok, body, octet_stream()));
+            if let Some(len) = len {
+                res.headers.set(ContentLength(len));
             }
+            Ok(res)
+        }
+        Err(e) => {
+            ticket.finish(&Err(e.clone()));
+            Ok(fetch_error_response(&e))
         }
     }
+}
 
-    // Ok(Response::with((status::Ok, "Ok")))
+/// Reports a download to the stats collector once it has been sent, so the
+/// recorded time covers the whole transfer to the client.
+struct Recorder {
+    stats: Collector,
+    /// Keeps shutting down from cutting the download off
+    _busy: shutdown::Busy,
+    name: String,
+    version: String,
+    remote_addr: Option<String>,
+    user_agent: Option<String>,
+    started: Instant,
 }
 
-fn stats_view() -> IronResult<Response> {
-    let db = Database::new(None::<&str>);
-    let stats = db.stats();
+impl Recorder {
+    fn record(&self, hit: bool, size: u64, upstream_time: Option<Duration>) {
+        record_metrics(hit, size);
+        self.stats.record(CargoRequest {
+            name: self.name.clone(),
+            version: self.version.clone(),
+            hit,
+            size: size as i64,
+            time: stats::now(),
+            duration_ms: self.started.elapsed().as_millis() as i64,
+            upstream_ms: upstream_time.map(|t| t.as_millis() as i64),
+            remote_addr: self.remote_addr.clone(),
+            user_agent: self.user_agent.clone(),
+        });
+    }
+}
+
+/// The client's address, without the IPv4-mapped prefix that listening on
+/// `::` adds.
+fn remote_ip(req: &Request) -> Option<String> {
+    if req.remote_addr == listen::unix_addr() {
+        return None;
+    }
+    let ip = match req.remote_addr.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    };
+    Some(ip.to_string())
+}
+
+fn serve_cached(file: File, recorder: Recorder) -> Response {
+    let len = file.metadata().map(|m| m.len()).ok();
+    let body: Box<dyn WriteBody> = Box::new(CachedCrate { file, recorder });
+    let mut res = Response::with((status::Ok, body, octet_stream()));
+    if let Some(len) = len {
+        res.headers.set(ContentLength(len));
+    }
+    res
+}
+
+fn octet_stream() -> Mime {
+    Mime(TopLevel::Application, SubLevel::Ext("octet-stream".into()), vec![])
+}
+
+/// Response body for a cache hit.
+struct CachedCrate {
+    file: File,
+    recorder: Recorder,
+}
+
+impl WriteBody for CachedCrate {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        let size = io::copy(&mut self.file, res)?;
+        self.recorder.record(true, size, None);
+        Ok(())
+    }
+}
+
+/// Response body for a cache miss, which sends the crate to the client while
+/// it is being downloaded into the cache.
+struct StreamingDownload {
+    download: Option<Download>,
+    ticket: Option<Ticket>,
+    recorder: Recorder,
+}
+
+impl WriteBody for StreamingDownload {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        let download = match self.download.take() {
+            Some(download) => download,
+            None => return Ok(()),
+        };
+        let result = download.finish(Some(res));
+        if let Some(ticket) = self.ticket.take() {
+            ticket.finish(&result.as_ref().map(|_| ()).map_err(|e| e.clone()));
+        }
+        match result {
+            Ok(fetched) => {
+                self.recorder
+                    .record(false, fetched.size, Some(fetched.upstream_time));
+                Ok(())
+            }
+            // Headers are already out, so all we can do is cut the client off
+            Err(e) => Err(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+fn record_metrics(hit: bool, size: u64) {
+    if hit {
+        metrics::CRATE_HITS.inc();
+        metrics::CRATE_HIT_BYTES.add(size);
+    } else {
+        metrics::CRATE_MISSES.inc();
+        metrics::CRATE_MISS_BYTES.add(size);
+    }
+}
+
+fn fetch_error_response(e: &FetchError) -> Response {
+    match *e {
+        FetchError::NotFound | FetchError::NotInIndex => {
+            Response::with((status::NotFound, "Crate not found"))
+        }
+        FetchError::Upstream(_) | FetchError::Checksum { .. } => Response::with((
+            status::BadGateway,
+            "Crates.io returned an invalid response",
+        )),
+        FetchError::Unavailable(_) => Response::with((
+            status::ServiceUnavailable,
+            "Couldn't fetch from Crates.io",
+        )),
+        FetchError::Io(_) => Response::with((
+            status::InternalServerError,
+            "Couldn't store the crate",
+        )),
+    }
+}
+
+fn metrics_view() -> IronResult<Response> {
     Ok(Response::with((
         status::Ok,
-        format!(
-            include_str!("stats.html"),
-            stats.downloads, stats.hits, stats.misses, stats.bandwidth_saved
+        metrics::render(),
+        Mime(
+            TopLevel::Text,
+            SubLevel::Plain,
+            vec![(Attr::Ext("version".into()), Value::Ext("0.0.4".into()))],
         ),
+    )))
+}
+
+/// The dashboard, which draws itself from the JSON endpoints.
+fn stats_view() -> IronResult<Response> {
+    Ok(Response::with((
+        status::Ok,
+        include_str!("stats.html"),
         Mime(TopLevel::Text, SubLevel::Html, vec![]),
     )))
 }
 
-fn stats_json() -> IronResult<Response> {
-    let db = Database::new(None::<&str>);
-    let stats = db.stats();
+/// State of the cache and the index, rather than of downloads.
+fn stats_status(config: &Config) -> IronResult<Response> {
+    let last_sync = metrics::INDEX_SYNC_LAST_SUCCESS.get();
+    Ok(Response::with((
+        status::Ok,
+        json!({
+            "version": crate_version!(),
+            "cache": {
+                "size": metrics::CACHE_SIZE.get(),
+                "max_size": config.max_cache_size,
+            },
+            "index": {
+                "last_sync": if last_sync > 0 { Some(last_sync) } else { None },
+                "last_sync_duration_ms": metrics::INDEX_SYNC_DURATION.get(),
+                "syncs": metrics::INDEX_SYNCS.get(),
+                "sync_failures": metrics::INDEX_SYNC_FAILURES.get(),
+                "refresh_interval": config.refresh_interval.as_secs(),
+            },
+        })
+        .to_string(),
+        Mime(TopLevel::Application, SubLevel::Json, vec![]),
+    )))
+}
+
+fn stats_export(req: &mut Request, db: &Database) -> IronResult<Response> {
+    let query = query_params(req);
+    let (from, to) = match stats::time_range(&query) {
+        Ok(range) => range,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let format = match query.get("format").map(|f| ExportFormat::from_str(f)) {
+        None => ExportFormat::Csv,
+        Some(Ok(format)) => format,
+        Some(Err(e)) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let body: Box<dyn WriteBody> = Box::new(Export {
+        db: db.clone(),
+        from,
+        to,
+        format,
+    });
+    let mut res = Response::with((
+        status::Ok,
+        body,
+        format.content_type().parse::<Mime>().unwrap(),
+    ));
+    res.headers.set_raw(
+        "Content-Disposition",
+        vec![format!("attachment; filename=\"downloads.{}\"", format.extension()).into_bytes()],
+    );
+    Ok(res)
+}
+
+/// Response body for `/stats/export`, written out as it is read from the
+/// database.
+struct Export {
+    db: Database,
+    from: i64,
+    to: i64,
+    format: ExportFormat,
+}
+
+impl WriteBody for Export {
+    fn write_body(&mut self, res: &mut dyn Write) -> io::Result<()> {
+        self.db
+            .export(self.from, self.to, self.format, res)
+            .map(|_| ())
+            .map_err(|e| io::Error::other(e.to_string()))
+    }
+}
+
+fn stats_misses(req: &mut Request, db: &Database) -> IronResult<Response> {
+    let limit = match limit_param(&query_params(req)) {
+        Ok(limit) => limit,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    Ok(Response::with((
+        status::Ok,
+        json!({ "misses": db.recent_misses(limit) }).to_string(),
+        Mime(TopLevel::Application, SubLevel::Json, vec![]),
+    )))
+}
+
+/// Query string parameters of a request; later values win.
+fn query_params(req: &Request) -> HashMap<String, String> {
+    req.url
+        .as_ref()
+        .query_pairs()
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect()
+}
+
+fn stats_json(req: &mut Request, db: &Database) -> IronResult<Response> {
+    let window = match Window::from_query(&query_params(req)) {
+        Ok(window) => window,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let stats = db.stats(&window);
     Ok(Response::with((
         status::Ok,
         stats.as_json(),
         Mime(TopLevel::Text, SubLevel::Json, vec![]),
     )))
 }
+
+fn stats_crates(req: &mut Request, db: &Database) -> IronResult<Response> {
+    let query = query_params(req);
+    let (from, to) = match stats::time_range(&query) {
+        Ok(range) => range,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let limit = match limit_param(&query) {
+        Ok(limit) => limit,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    Ok(Response::with((
+        status::Ok,
+        json!({
+            "from": from,
+            "to": to,
+            "most_requested": db.top_crates(from, to, limit, false),
+            "most_missed": db.top_crates(from, to, limit, true),
+        })
+        .to_string(),
+        Mime(TopLevel::Application, SubLevel::Json, vec![]),
+    )))
+}
+
+/// The `limit` query parameter, 10 by default.
+fn limit_param(query: &HashMap<String, String>) -> Result<u32, &'static str> {
+    match query.get("limit").map(|l| u32::from_str(l)) {
+        None => Ok(10),
+        Some(Ok(limit)) if limit <= 1000 => Ok(limit),
+        Some(_) => Err("limit must be a number up to 1000"),
+    }
+}
+
+fn stats_crate(req: &mut Request, db: &Database) -> IronResult<Response> {
+    let (from, to) = match stats::time_range(&query_params(req)) {
+        Ok(range) => range,
+        Err(e) => return Ok(Response::with((status::BadRequest, e))),
+    };
+    let crate_name = req
+        .extensions
+        .get::<Router>()
+        .unwrap()
+        .find("crate_name")
+        .unwrap_or("");
+    let versions = match db.crate_versions(crate_name, from, to) {
+        Some(versions) => versions,
+        None => return Ok(Response::with((status::NotFound, "Crate not found"))),
+    };
+    Ok(Response::with((
+        status::Ok,
+        json!({
+            "name": crate_name,
+            "from": from,
+            "to": to,
+            "downloads": versions.iter().map(|v| v.downloads).sum::<i64>(),
+            "hits": versions.iter().map(|v| v.hits).sum::<i64>(),
+            "misses": versions.iter().map(|v| v.misses).sum::<i64>(),
+            "bytes_served": versions.iter().map(|v| v.bytes_served).sum::<i64>(),
+            "versions": versions,
+        })
+        .to_string(),
+        Mime(TopLevel::Application, SubLevel::Json, vec![]),
+    )))
+}
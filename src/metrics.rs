@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// A counter or gauge, updated in place from wherever the event happens.
+pub struct Metric(AtomicU64);
+
+impl Metric {
+    const fn new() -> Metric {
+        Metric(AtomicU64::new(0))
+    }
+
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, n: u64) {
+        self.0.store(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+pub static CRATE_HITS: Metric = Metric::new();
+pub static CRATE_MISSES: Metric = Metric::new();
+pub static CRATE_HIT_BYTES: Metric = Metric::new();
+pub static CRATE_MISS_BYTES: Metric = Metric::new();
+pub static GIT_INDEX_REQUESTS: Metric = Metric::new();
+pub static SPARSE_INDEX_REQUESTS: Metric = Metric::new();
+pub static INDEX_SYNCS: Metric = Metric::new();
+pub static INDEX_SYNC_FAILURES: Metric = Metric::new();
+/// Milliseconds taken by the most recent index sync
+pub static INDEX_SYNC_DURATION: Metric = Metric::new();
+/// Seconds since the epoch of the last successful index sync
+pub static INDEX_SYNC_LAST_SUCCESS: Metric = Metric::new();
+/// Bytes of crates stored on disk
+pub static CACHE_SIZE: Metric = Metric::new();
+
+/// Failed upstream fetches, by reason
+static FETCH_FAILURES: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+
+pub fn fetch_failed(reason: &'static str) {
+    *FETCH_FAILURES.lock().unwrap().entry(reason).or_insert(0) += 1;
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str, value: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let counters = [
+        ("cargo_cacher_crate_hits_total", "Crate downloads served from the cache", &CRATE_HITS),
+        ("cargo_cacher_crate_misses_total", "Crate downloads fetched from upstream", &CRATE_MISSES),
+        ("cargo_cacher_crate_hit_bytes_total", "Bytes of crates served from the cache", &CRATE_HIT_BYTES),
+        ("cargo_cacher_crate_miss_bytes_total", "Bytes of crates fetched from upstream", &CRATE_MISS_BYTES),
+        ("cargo_cacher_git_index_requests_total", "Requests to the git index", &GIT_INDEX_REQUESTS),
+        ("cargo_cacher_sparse_index_requests_total", "Requests to the sparse index", &SPARSE_INDEX_REQUESTS),
+        ("cargo_cacher_index_syncs_total", "Syncs of the git index with upstream", &INDEX_SYNCS),
+        ("cargo_cacher_index_sync_failures_total", "Failed syncs of the git index", &INDEX_SYNC_FAILURES),
+    ];
+    for &(name, help, counter) in counters.iter() {
+        metric(&mut out, name, "counter", help, &counter.get().to_string());
+    }
+
+    let _ = writeln!(out, "# HELP cargo_cacher_upstream_fetch_failures_total Failed crate fetches from upstream");
+    let _ = writeln!(out, "# TYPE cargo_cacher_upstream_fetch_failures_total counter");
+    for (reason, count) in FETCH_FAILURES.lock().unwrap().iter() {
+        let _ = writeln!(
+            out,
+            "cargo_cacher_upstream_fetch_failures_total{{reason=\"{}\"}} {}",
+            reason, count
+        );
+    }
+
+    metric(
+        &mut out,
+        "cargo_cacher_index_sync_duration_seconds",
+        "gauge",
+        "Time taken by the most recent git index sync",
+        &format!("{:.3}", INDEX_SYNC_DURATION.get() as f64 / 1000.0),
+    );
+    metric(
+        &mut out,
+        "cargo_cacher_index_sync_last_success_timestamp_seconds",
+        "gauge",
+        "When the git index was last synced successfully",
+        &INDEX_SYNC_LAST_SUCCESS.get().to_string(),
+    );
+    metric(
+        &mut out,
+        "cargo_cacher_cache_size_bytes",
+        "gauge",
+        "Disk space used by cached crates",
+        &CACHE_SIZE.get().to_string(),
+    );
+    out
+}
@@ -0,0 +1,165 @@
+use std::error::Error;
+use std::fmt;
+
+use rusqlite::Connection;
+
+/// Schema changes for the statistics database, in order. The schema version
+/// of a database (SQLite's `user_version`) is the number of these that have
+/// been applied to it. Existing entries must never be changed, only appended
+/// to.
+const MIGRATIONS: &[&str] = &[
+    // 1: The original schema, which databases created before versioning
+    // already have
+    "CREATE TABLE IF NOT EXISTS crates (
+         id INTEGER PRIMARY KEY AUTOINCREMENT,
+         name TEXT
+     );
+     CREATE TABLE IF NOT EXISTS crate_versions (
+         id INTEGER PRIMARY KEY AUTOINCREMENT,
+         version TEXT,
+         crate_id INTEGER
+     );
+     CREATE TABLE IF NOT EXISTS downloads (
+         version_id INTEGER,
+         time TIMESTAMP,
+         hit BOOLEAN,
+         size BIGINT
+     );
+     CREATE UNIQUE INDEX IF NOT EXISTS unique_crate_names
+     ON crates (name);
+     CREATE UNIQUE INDEX IF NOT EXISTS unique_crate_versions
+     ON crate_versions (crate_id, version);",
+    // 2: Store download times as seconds since the epoch rather than dates
+    "UPDATE downloads SET time = CAST(strftime('%s', time) AS INTEGER)
+     WHERE typeof(time) = 'text';
+     CREATE INDEX downloads_time ON downloads (time);",
+    // 3: How long each download took to serve, and to fetch from upstream
+    "ALTER TABLE downloads ADD COLUMN duration_ms INTEGER;
+     ALTER TABLE downloads ADD COLUMN upstream_ms INTEGER;",
+    // 4: Who made each download
+    "ALTER TABLE downloads ADD COLUMN remote_addr TEXT;
+     ALTER TABLE downloads ADD COLUMN user_agent TEXT;",
+    // 5: Daily totals that old downloads are rolled up into, and a view
+    // combining them with the downloads not rolled up yet
+    "CREATE TABLE download_rollups (
+         version_id INTEGER NOT NULL,
+         day INTEGER NOT NULL,
+         downloads INTEGER NOT NULL,
+         hits INTEGER NOT NULL,
+         bytes_served INTEGER NOT NULL,
+         bytes_saved INTEGER NOT NULL,
+         last_time INTEGER NOT NULL,
+         PRIMARY KEY (version_id, day)
+     );
+     CREATE INDEX download_rollups_day ON download_rollups (day);
+     CREATE VIEW all_downloads AS
+         SELECT version_id, time, 1 AS downloads, hit AS hits, size AS bytes_served,
+                CASE WHEN hit THEN size ELSE 0 END AS bytes_saved, time AS last_time
+         FROM downloads
+         UNION ALL
+         SELECT version_id, day, downloads, hits, bytes_served, bytes_saved, last_time
+         FROM download_rollups;",
+];
+
+/// The database was written by a newer cargo-cacher than this one.
+#[derive(Debug)]
+pub struct SchemaTooNew {
+    found: u32,
+    supported: u32,
+}
+
+impl fmt::Display for SchemaTooNew {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "database schema version {} is newer than the supported version {}",
+            self.found, self.supported
+        )
+    }
+}
+
+impl Error for SchemaTooNew {}
+
+/// Brings the database schema up to date, one migration per transaction.
+pub fn migrate(conn: &mut Connection) -> Result<(), Box<dyn Error>> {
+    let supported = MIGRATIONS.len() as u32;
+    let found: u32 = conn.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |row| row.get(0))?;
+    if found > supported {
+        return Err(Box::new(SchemaTooNew { found, supported }));
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        let version = version as u32 + 1;
+        info!("Migrating the stats database to schema version {}", version);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(conn: &Connection) -> u32 {
+        conn.query_row("PRAGMA user_version", rusqlite::NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_a_database_from_before_versioning() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // What older versions created, with times stored by `date('now')`
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO crates (name) VALUES ('serde');
+             INSERT INTO crate_versions (version, crate_id) VALUES ('1.0.0', 1);
+             INSERT INTO downloads VALUES (1, '2020-01-02', 1, 100);",
+        )
+        .unwrap();
+        assert_eq!(user_version(&conn), 0);
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(user_version(&conn), MIGRATIONS.len() as u32);
+        let (time, kind): (i64, String) = conn
+            .query_row("SELECT time, typeof(time) FROM downloads", rusqlite::NO_PARAMS, |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(time, 1_577_923_200);
+        assert_eq!(kind, "integer");
+        let downloads: i64 = conn
+            .query_row("SELECT SUM(downloads) FROM all_downloads", rusqlite::NO_PARAMS, |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(downloads, 1);
+    }
+
+    #[test]
+    fn migrating_twice_changes_nothing() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len() as u32);
+    }
+
+    #[test]
+    fn refuses_a_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let newer = MIGRATIONS.len() as u32 + 1;
+        conn.execute_batch(&format!("PRAGMA user_version = {}", newer))
+            .unwrap();
+
+        let err = migrate(&mut conn).unwrap_err();
+
+        assert!(err.downcast_ref::<SchemaTooNew>().is_some());
+        assert_eq!(user_version(&conn), newer);
+        let tables: i64 = conn
+            .query_row("SELECT count(*) FROM sqlite_master", rusqlite::NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(tables, 0);
+    }
+}
@@ -0,0 +1,122 @@
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use hyper;
+use hyper::net::NetworkListener;
+use libc;
+
+use index_sync;
+use stats::Collector;
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Listening sockets, with the path of the ones that are Unix sockets.
+static LISTENERS: Mutex<Vec<(OwnedFd, Option<PathBuf>)>> = Mutex::new(Vec::new());
+
+/// Downloads being served.
+static ACTIVE: Mutex<usize> = Mutex::new(0);
+static IDLE: Condvar = Condvar::new();
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// Registers a listening socket to close when shutting down, and for Unix
+/// sockets the file to remove.
+pub fn add_listener(fd: OwnedFd, path: Option<PathBuf>) {
+    LISTENERS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push((fd, path));
+}
+
+/// Marks a download as in progress until dropped, so that shutting down
+/// waits for it.
+pub struct Busy(());
+
+pub fn busy() -> Busy {
+    *ACTIVE.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+    Busy(())
+}
+
+impl Drop for Busy {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap_or_else(PoisonError::into_inner);
+        *active -= 1;
+        if *active == 0 {
+            IDLE.notify_all();
+        }
+    }
+}
+
+/// Stops accepting connections, waits up to `timeout` for downloads in
+/// progress and for an index sync to finish, then writes out pending
+/// statistics. New index syncs are held off until the process exits.
+pub fn shut_down(collector: &Collector, timeout: Duration) {
+    STOPPING.store(true, Ordering::SeqCst);
+    for (fd, path) in LISTENERS.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+        // Refuses new connections and wakes up threads blocked in accept()
+        unsafe {
+            libc::shutdown(fd.as_raw_fd(), libc::SHUT_RDWR);
+        }
+        if let Some(path) = path {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Couldn't remove {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    let active = ACTIVE.lock().unwrap_or_else(PoisonError::into_inner);
+    if *active > 0 {
+        println!("Shutting down, waiting for {} downloads to finish", *active);
+    }
+    let (active, _) = IDLE
+        .wait_timeout_while(active, timeout, |active| *active > 0)
+        .unwrap_or_else(PoisonError::into_inner);
+    if *active > 0 {
+        warn!("Gave up waiting for {} downloads", *active);
+    }
+    drop(active);
+
+    let _paused = index_sync::pause();
+    collector.flush();
+}
+
+/// Stops handing out connections once shutting down.
+#[derive(Clone)]
+pub struct Gate<L>(pub L);
+
+impl<L: NetworkListener> NetworkListener for Gate<L> {
+    type Stream = L::Stream;
+
+    fn accept(&mut self) -> hyper::Result<L::Stream> {
+        let accepted = self.0.accept();
+        if stopping() {
+            drop(accepted);
+            // hyper retries failed accepts straight away, so put this thread
+            // to sleep for good instead
+            loop {
+                thread::park();
+            }
+        }
+        accepted
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    fn set_read_timeout(&mut self, dur: Option<Duration>) {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&mut self, dur: Option<Duration>) {
+        self.0.set_write_timeout(dur)
+    }
+}
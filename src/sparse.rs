@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use iron::headers::{ETag, EntityTag, IfNoneMatch};
+
+// Iron Stuff
+use iron::prelude::*;
+use iron::status;
+
+use iron::mime::{Mime, SubLevel, TopLevel};
+
+use crates::index_file;
+use index_sync::registry_config;
+use metrics;
+use Config;
+
+/// Serves `config.json` for the sparse index. It is generated rather than
+/// read from the git checkout so that it is available before the first sync.
+pub fn config_json(config: &Config) -> IronResult<Response> {
+    Ok(Response::with((
+        status::Ok,
+        registry_config(&config.extern_url),
+        Mime(TopLevel::Application, SubLevel::Json, vec![]),
+    )))
+}
+
+/// Serves a single crate's index file out of the checked out git index,
+/// as requested by cargo's `sparse+http` protocol.
+pub fn sparse(req: &mut Request, config: &Config) -> IronResult<Response> {
+    debug!("Sparse index request: {:?}", req.url);
+    metrics::SPARSE_INDEX_REQUESTS.inc();
+    // The first segment is the `sparse` mount point itself
+    let segments: Vec<String> = req
+        .url
+        .path()
+        .iter()
+        .skip(1)
+        .map(|s| s.to_lowercase())
+        .collect();
+    let path = match segments
+        .last()
+        .and_then(|name| index_file(&config.git_index_path, name))
+    {
+        Some(path) => path,
+        None => return Ok(Response::with((status::NotFound, "Not found"))),
+    };
+    // Only serve the file if it was asked for at its canonical location,
+    // which also keeps requests from wandering outside of the index
+    let requested: PathBuf = segments.iter().collect();
+    if Path::new(&config.git_index_path).join(requested) != path {
+        return Ok(Response::with((status::NotFound, "Not found")));
+    }
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(Response::with((status::NotFound, "Not found"))),
+    };
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let etag = EntityTag::new(false, format!("{:x}-{:x}", modified, metadata.len()));
+    let fresh = match req.headers.get::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|t| t.weak_eq(&etag)),
+        None => false,
+    };
+    let mut res = if fresh {
+        Response::with(status::NotModified)
+    } else {
+        Response::with((
+            status::Ok,
+            path,
+            Mime(TopLevel::Text, SubLevel::Plain, vec![]),
+        ))
+    };
+    res.headers.set(ETag(etag));
+    Ok(res)
+}
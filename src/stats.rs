@@ -1,212 +1,927 @@
-use std::sync::mpsc::{SyncSender, sync_channel};
-use std::thread;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, sleep};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use super::CargoRequest;
+use migrations::migrate;
 
-use rusqlite;
-use rusqlite::params;
+use humantime;
+use serde_json;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{self, params, Connection, ToSql};
 
+#[derive(Clone)]
 pub struct Database {
-    conn: rusqlite::Connection,
+    pool: Pool<SqliteConnectionManager>,
+}
+
+/// Seconds since the epoch, which is how times are stored in the database.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bucket {
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    pub fn seconds(self) -> i64 {
+        match self {
+            Bucket::Hour => 60 * 60,
+            Bucket::Day => 24 * 60 * 60,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+        }
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Bucket, String> {
+        match s {
+            "hour" => Ok(Bucket::Hour),
+            "day" => Ok(Bucket::Day),
+            _ => Err(format!("unknown bucket \"{}\", expected hour or day", s)),
+        }
+    }
+}
+
+/// A time range to report statistics over, split into buckets. `from` is
+/// rounded down to the start of its bucket and `to` is exclusive.
+#[derive(Clone, Copy, Debug)]
+pub struct Window {
+    pub from: i64,
+    pub to: i64,
+    pub bucket: Bucket,
+}
+
+/// Limits how much work a single stats request can ask for.
+const MAX_BUCKETS: i64 = 10_000;
+
+impl Window {
+    pub fn new(from: i64, to: i64, bucket: Bucket) -> Result<Window, String> {
+        let from = from - from.rem_euclid(bucket.seconds());
+        if from >= to {
+            return Err("from must be before to".into());
+        }
+        if (to - from) / bucket.seconds() > MAX_BUCKETS {
+            return Err(format!("too many buckets, use at most {}", MAX_BUCKETS));
+        }
+        Ok(Window { from, to, bucket })
+    }
+
+    /// Reads the `from`, `to` and `bucket` query parameters, defaulting to
+    /// the last 24 hours by the hour. Times can be given as seconds since the
+    /// epoch, as RFC 3339 dates, or as a duration before now like "7days".
+    pub fn from_query(query: &HashMap<String, String>) -> Result<Window, String> {
+        let (from, to) = time_range(query)?;
+        let bucket = match query.get("bucket") {
+            Some(bucket) => bucket.parse()?,
+            None => Bucket::Hour,
+        };
+        Window::new(from, to, bucket)
+    }
+
+    /// The start of each bucket in the window.
+    fn buckets(&self) -> impl Iterator<Item = i64> {
+        let step = self.bucket.seconds();
+        let from = self.from;
+        (0..(self.to - self.from + step - 1) / step).map(move |i| from + i * step)
+    }
+}
+
+/// Reads the `from` and `to` query parameters, see `Window::from_query`.
+pub fn time_range(query: &HashMap<String, String>) -> Result<(i64, i64), String> {
+    let now = now();
+    let to = match query.get("to") {
+        Some(to) => parse_time(to, now)?,
+        None => now,
+    };
+    let from = match query.get("from") {
+        Some(from) => parse_time(from, now)?,
+        None => to - Bucket::Day.seconds(),
+    };
+    if from >= to {
+        return Err("from must be before to".into());
+    }
+    Ok((from, to))
+}
+
+fn parse_time(value: &str, now: i64) -> Result<i64, String> {
+    if let Ok(seconds) = i64::from_str(value) {
+        return Ok(seconds);
+    }
+    if let Ok(time) = humantime::parse_rfc3339_weak(value) {
+        return Ok(time
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0));
+    }
+    match humantime::parse_duration(value) {
+        Ok(ago) => Ok(now - ago.as_secs() as i64),
+        Err(_) => Err(format!("couldn't parse time \"{}\"", value)),
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sample {
+    /// Start of the bucket, in seconds since the epoch
+    pub time: i64,
+    pub downloads: i64,
+    pub hits: i64,
+    pub misses: i64,
+    pub bandwidth_saved: i64,
+}
+
+impl Sample {
+    fn at(time: i64) -> Sample {
+        Sample {
+            time,
+            downloads: 0,
+            hits: 0,
+            misses: 0,
+            bandwidth_saved: 0,
+        }
+    }
+}
+
+/// Downloads of a crate, or of one version of a crate.
+#[derive(Debug, Serialize)]
+pub struct CrateStats {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    pub downloads: i64,
+    pub hits: i64,
+    pub misses: i64,
+    pub bytes_served: i64,
+    /// Last download in the range, in seconds since the epoch
+    pub last_served: i64,
+}
+
+/// A download that had to be fetched from upstream.
+#[derive(Debug, Serialize)]
+pub struct Miss {
+    pub name: String,
+    pub version: String,
+    /// Seconds since the epoch
+    pub time: i64,
+    pub size: i64,
+    pub upstream_ms: Option<i64>,
+}
+
+/// Nearest-rank percentiles of a set of timings, in milliseconds.
+#[derive(Debug, Default, Serialize)]
+pub struct Percentiles {
+    pub count: i64,
+    pub p50: Option<i64>,
+    pub p95: Option<i64>,
+    pub p99: Option<i64>,
+}
+
+/// How long downloads took: to serve cache hits, to serve cache misses, and
+/// the part of each miss spent waiting on upstream.
+#[derive(Debug, Default, Serialize)]
+pub struct Latency {
+    pub hit: Percentiles,
+    pub miss: Percentiles,
+    pub upstream: Percentiles,
+}
+
+/// Downloads by one group of clients.
+#[derive(Debug, Serialize)]
+pub struct Usage {
+    pub name: String,
+    pub downloads: i64,
+    pub hits: i64,
+    pub misses: i64,
+    pub bytes_served: i64,
+}
+
+/// The network a client is in: its /24 for IPv4 or /48 for IPv6.
+pub fn subnet(remote_addr: Option<&str>) -> String {
+    match remote_addr.and_then(|addr| IpAddr::from_str(addr).ok()) {
+        Some(IpAddr::V4(ip)) => {
+            let o = ip.octets();
+            format!("{}.{}.{}.0/24", o[0], o[1], o[2])
+        }
+        Some(IpAddr::V6(ip)) => {
+            let s = ip.segments();
+            format!("{:x}:{:x}:{:x}::/48", s[0], s[1], s[2])
+        }
+        None => "unknown".into(),
+    }
+}
+
+/// The cargo version out of a `User-Agent` like "cargo 1.75.0 (1d8b05cdd
+/// 2023-11-20)".
+pub fn cargo_version(user_agent: Option<&str>) -> String {
+    let user_agent = match user_agent {
+        Some(user_agent) => user_agent,
+        None => return "unknown".into(),
+    };
+    let mut words = user_agent.split([' ', '/']);
+    match (words.next(), words.next()) {
+        (Some("cargo"), Some(version)) if !version.is_empty() => version.to_string(),
+        _ => "other".into(),
+    }
 }
 
 #[derive(Debug)]
 pub struct Statistics {
+    pub window: Window,
     pub downloads: i64,
     pub hits: i64,
     pub misses: i64,
     pub bandwidth_saved: i64,
+    pub latency: Latency,
+    pub by_subnet: Vec<Usage>,
+    pub by_cargo_version: Vec<Usage>,
+    pub series: Vec<Sample>,
 }
 
 impl Statistics {
     pub fn as_json(&self) -> String {
         json!({
+            "from": self.window.from,
+            "to": self.window.to,
+            "bucket": self.window.bucket.name(),
             "downloads": self.downloads,
             "hits": self.hits,
             "misses": self.misses,
-            "bandwidth_saved": self.bandwidth_saved
+            "bandwidth_saved": self.bandwidth_saved,
+            "latency_ms": self.latency,
+            "by_subnet": self.by_subnet,
+            "by_cargo_version": self.by_cargo_version,
+            "series": self.series,
         }).to_string()
     }
 }
 
 impl Database {
-    pub fn new<T: Into<String>>(connection_string: Option<T>) -> Database {
+    /// Opens (creating if needed) the statistics database at `path` and
+    /// brings its schema up to date. Clones share one connection pool.
+    pub fn open(path: &str) -> Result<Database, Box<dyn Error>> {
+        if let Some(dir) = Path::new(path).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Open and migrate once up front, so a bad path fails right away
+        // instead of after the pool's connection timeout
+        let mut conn = Connection::open(path)?;
+        Database::init(&mut conn)?;
+        migrate(&mut conn)?;
+        let manager = SqliteConnectionManager::file(path).with_init(Database::init);
+        let pool = Pool::builder().max_size(8).build(manager)?;
+        Ok(Database { pool })
+    }
 
-        let connection_string: String = if let Some(s) = connection_string {
-            s.into()
-        } else {
-            "file::memory:?cache=shared".to_string()
-            // "database.sqlite".into()
+    fn init(conn: &mut Connection) -> rusqlite::Result<()> {
+        // Let the HTTP handlers read while the collector writes
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")
+    }
+
+    /// Downloads, hits, misses and bytes saved within `window`, in total and
+    /// per bucket.
+    pub fn stats(&self, window: &Window) -> Statistics {
+        let mut stats = Statistics {
+            window: *window,
+            downloads: 0,
+            hits: 0,
+            misses: 0,
+            bandwidth_saved: 0,
+            latency: Latency::default(),
+            by_subnet: vec![],
+            by_cargo_version: vec![],
+            series: window.buckets().map(Sample::at).collect(),
         };
-        let conn = rusqlite::Connection::open(&connection_string).unwrap();
-        conn.execute("
-            CREATE TABLE IF NOT EXISTS crates (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT
-            );",
-                     params![])
-            .unwrap();
-        conn.execute("
-             CREATE TABLE IF NOT EXISTS crate_versions (
-                 id INTEGER PRIMARY KEY AUTOINCREMENT,
-                 version TEXT,
-                 crate_id INTEGER
-             );",
-                     params![])
-            .unwrap();
-        conn.execute("
-             CREATE TABLE IF NOT EXISTS downloads (
-                 version_id INTEGER,
-                 time TIMESTAMP,
-                 hit BOOLEAN,
-                 size BIGINT
-             );",
-                     params![])
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            _ => return stats,
+        };
+        stats.latency = Latency {
+            hit: Database::percentiles(&conn, "duration_ms", true, window),
+            miss: Database::percentiles(&conn, "duration_ms", false, window),
+            upstream: Database::percentiles(&conn, "upstream_ms", false, window),
+        };
+        stats.by_subnet = Database::usage(&conn, "remote_addr", subnet, window);
+        stats.by_cargo_version = Database::usage(&conn, "user_agent", cargo_version, window);
+        let mut stmt = conn
+            .prepare("SELECT (time - ?1) / ?3, SUM(downloads), SUM(hits), SUM(bytes_saved)
+                      FROM all_downloads
+                      WHERE time >= ?1 AND time < ?2
+                      GROUP BY 1")
             .unwrap();
+        let rows = match stmt.query_map(params![window.from, window.to, window.bucket.seconds()],
+                                        |row| {
+                                            Ok((row.get::<_, i64>(0)?,
+                                                row.get::<_, i64>(1)?,
+                                                row.get::<_, i64>(2)?,
+                                                row.get::<_, i64>(3)?))
+                                        }) {
+            Ok(s) => s,
+            _ => return stats,
+        };
+        for (bucket, downloads, hits, bandwidth_saved) in rows.flatten() {
+            if let Some(sample) = stats.series.get_mut(bucket as usize) {
+                sample.downloads = downloads;
+                sample.hits = hits;
+                sample.misses = downloads - hits;
+                sample.bandwidth_saved = bandwidth_saved;
+            }
+            stats.downloads += downloads;
+            stats.hits += hits;
+            stats.misses += downloads - hits;
+            stats.bandwidth_saved += bandwidth_saved;
+        }
+        stats
+    }
 
-        conn.execute("
-            CREATE UNIQUE INDEX IF NOT EXISTS unique_crate_names
-            ON crates (name)",
-                     params![])
-            .unwrap();
+    /// Percentiles of `column` over the hits or misses within `window`.
+    /// Downloads recorded before timings were kept are left out.
+    fn percentiles(conn: &Connection, column: &str, hit: bool, window: &Window) -> Percentiles {
+        let filter = format!("FROM downloads
+                              WHERE time >= ?1 AND time < ?2 AND hit = ?3
+                                  AND {} IS NOT NULL",
+                             column);
+        let count: i64 = conn.query_row(&format!("SELECT count(*) {}", filter),
+                                        params![window.from, window.to, hit],
+                                        |row| row.get(0))
+            .unwrap_or(0);
+        let rank = |p: i64| -> Option<i64> {
+            if count == 0 {
+                return None;
+            }
+            let offset = (count * p + 99) / 100 - 1;
+            conn.query_row(&format!("SELECT {0} {1} ORDER BY {0} LIMIT 1 OFFSET ?4",
+                                    column,
+                                    filter),
+                           params![window.from, window.to, hit, offset],
+                           |row| row.get(0))
+                .ok()
+        };
+        Percentiles {
+            count,
+            p50: rank(50),
+            p95: rank(95),
+            p99: rank(99),
+        }
+    }
 
-        conn.execute("
-            CREATE UNIQUE INDEX IF NOT EXISTS unique_crate_versions
-            ON crate_versions (crate_id, version)",
-                     params![])
+    /// Downloads within `window` grouped by `column`, after mapping its
+    /// values with `group`. Most downloads first.
+    fn usage(conn: &Connection,
+             column: &str,
+             group: fn(Option<&str>) -> String,
+             window: &Window)
+             -> Vec<Usage> {
+        let mut stmt = conn
+            .prepare(&format!("SELECT {}, count(*), COALESCE(SUM(hit), 0),
+                                      COALESCE(SUM(size), 0)
+                               FROM downloads
+                               WHERE time >= ?1 AND time < ?2
+                               GROUP BY 1",
+                              column))
             .unwrap();
-        Database { conn: conn }
+        let rows = match stmt.query_map(params![window.from, window.to], |row| {
+            Ok((row.get::<_, Option<String>>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?))
+        }) {
+            Ok(s) => s,
+            _ => return vec![],
+        };
+        let mut groups: HashMap<String, Usage> = HashMap::new();
+        for (value, downloads, hits, bytes_served) in rows.flatten() {
+            let name = group(value.as_deref());
+            let usage = groups.entry(name.clone()).or_insert(Usage {
+                name,
+                downloads: 0,
+                hits: 0,
+                misses: 0,
+                bytes_served: 0,
+            });
+            usage.downloads += downloads;
+            usage.hits += hits;
+            usage.misses += downloads - hits;
+            usage.bytes_served += bytes_served;
+        }
+        let mut usage: Vec<Usage> = groups.into_values().collect();
+        usage.sort_by(|a, b| b.downloads.cmp(&a.downloads).then_with(|| a.name.cmp(&b.name)));
+        usage
+    }
+
+    /// The crates downloaded the most between `from` and `to`, either
+    /// overall or counting only cache misses.
+    pub fn top_crates(&self, from: i64, to: i64, limit: u32, by_misses: bool) -> Vec<CrateStats> {
+        let order = if by_misses { "misses" } else { "total" };
+        self.crate_stats(&format!("SELECT crates.name, NULL,
+                                          SUM(d.downloads) AS total,
+                                          SUM(d.downloads) - SUM(d.hits) AS misses,
+                                          SUM(d.bytes_served),
+                                          MAX(d.last_time)
+                                   FROM all_downloads AS d
+                                   INNER JOIN crate_versions
+                                       ON crate_versions.id = d.version_id
+                                   INNER JOIN crates ON crates.id = crate_versions.crate_id
+                                   WHERE d.time >= ?1 AND d.time < ?2
+                                   GROUP BY crates.id
+                                   HAVING {0} > 0
+                                   ORDER BY {0} DESC, crates.name
+                                   LIMIT ?3",
+                                  order),
+                         &[&from, &to, &limit])
     }
 
-    pub fn stats(&self) -> Statistics {
-        let downloads = self.downloads("24 hours");
-        let hits = self.hits("24 hours");
-        let misses = downloads - hits;
-        let bandwidth_saved = self.bandwidth_saved("24 hours");
-        Statistics {
-            downloads: downloads as i64,
-            hits: hits as i64,
-            misses: misses as i64,
-            bandwidth_saved: bandwidth_saved as i64,
+    /// Downloads of each version of a crate between `from` and `to`, or
+    /// `None` if the crate has never been downloaded.
+    pub fn crate_versions(&self, name: &str, from: i64, to: i64) -> Option<Vec<CrateStats>> {
+        let versions = self.crate_stats("SELECT crates.name, crate_versions.version,
+                                                COALESCE(SUM(d.downloads), 0),
+                                                COALESCE(SUM(d.downloads) - SUM(d.hits), 0),
+                                                COALESCE(SUM(d.bytes_served), 0),
+                                                COALESCE(MAX(d.last_time), 0)
+                                         FROM crates
+                                         INNER JOIN crate_versions
+                                             ON crate_versions.crate_id = crates.id
+                                         LEFT JOIN all_downloads AS d
+                                             ON d.version_id = crate_versions.id
+                                             AND d.time >= ?2 AND d.time < ?3
+                                         WHERE crates.name = ?1
+                                         GROUP BY crate_versions.id
+                                         ORDER BY 3 DESC, crate_versions.version",
+                                        &[&name, &from, &to]);
+        if versions.is_empty() {
+            None
+        } else {
+            Some(versions)
         }
     }
 
-    pub fn downloads<T: Into<String>>(&self, time: T) -> i32 {
-        let mut stmt = self.conn
-            .prepare("SELECT count(*) FROM downloads WHERE time > date('now') - $1")
+    /// The latest cache misses, newest first.
+    pub fn recent_misses(&self, limit: u32) -> Vec<Miss> {
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            _ => return vec![],
+        };
+        let mut stmt = conn
+            .prepare("SELECT crates.name, crate_versions.version, downloads.time,
+                             downloads.size, downloads.upstream_ms
+                      FROM downloads
+                      INNER JOIN crate_versions ON crate_versions.id = downloads.version_id
+                      INNER JOIN crates ON crates.id = crate_versions.crate_id
+                      WHERE NOT downloads.hit
+                      ORDER BY downloads.time DESC, downloads.rowid DESC
+                      LIMIT ?1")
             .unwrap();
-        let rows = match stmt.query_map(&[&time.into()], |row| row.get(0)) {
+        let rows = match stmt.query_map(params![limit], |row| {
+            Ok(Miss {
+                name: row.get(0)?,
+                version: row.get(1)?,
+                time: row.get(2)?,
+                size: row.get(3)?,
+                upstream_ms: row.get(4)?,
+            })
+        }) {
             Ok(s) => s,
-            _ => return 0,
+            _ => return vec![],
         };
-        for record in rows {
-            if let Ok(count) = record {
-                return count;
-            }
-        }
-        0
+        rows.flatten().collect()
     }
 
-    pub fn hits<T: Into<String>>(&self, time: T) -> i32 {
-        let mut stmt = self.conn
-            .prepare("SELECT count(*) FROM downloads WHERE time > date('now') - $1 AND hit = 1")
-            .unwrap();
-        let rows = match stmt.query_map(&[&time.into()], |row| row.get(0)) {
+    fn crate_stats(&self, sql: &str, params: &[&dyn ToSql]) -> Vec<CrateStats> {
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            _ => return vec![],
+        };
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = match stmt.query_map(params, |row| {
+            let downloads: i64 = row.get(2)?;
+            let misses: i64 = row.get(3)?;
+            Ok(CrateStats {
+                name: row.get(0)?,
+                version: row.get(1)?,
+                downloads,
+                hits: downloads - misses,
+                misses,
+                bytes_served: row.get(4)?,
+                last_served: row.get(5)?,
+            })
+        }) {
             Ok(s) => s,
-            _ => return 0,
+            _ => return vec![],
         };
-        for record in rows {
-            if let Ok(count) = record {
-                return count;
-            }
-        }
-        0
+        rows.flatten().collect()
     }
 
-    pub fn bandwidth_saved<T: Into<String>>(&self, time: T) -> i64 {
-        let mut stmt = self.conn
-            .prepare("SELECT COALESCE(sum(size), 0) FROM downloads WHERE time > date('now') - $1 \
-                      AND hit = 1")
+    /// When each crate version was last served, in seconds since the epoch.
+    pub fn last_served(&self) -> HashMap<(String, String), i64> {
+        let conn = match self.pool.get() {
+            Ok(conn) => conn,
+            _ => return HashMap::new(),
+        };
+        let mut stmt = conn
+            .prepare("SELECT crates.name, crate_versions.version, MAX(d.last_time)
+                      FROM all_downloads AS d
+                      INNER JOIN crate_versions ON crate_versions.id = d.version_id
+                      INNER JOIN crates ON crates.id = crate_versions.crate_id
+                      GROUP BY crate_versions.id")
             .unwrap();
-        let rows = match stmt.query_map(&[&time.into()], |row| row.get(0)) {
+        let rows = match stmt.query_map(params![], |row| {
+            Ok(((row.get(0)?, row.get(1)?), row.get(2)?))
+        }) {
             Ok(s) => s,
-            _ => return 0,
+            _ => return HashMap::new(),
         };
-        for record in rows {
-            if let Ok(count) = record {
-                return count;
+        rows.flatten().collect()
+    }
+
+    fn crate_id(conn: &Connection, name: &str) -> rusqlite::Result<i64> {
+        conn.prepare_cached("INSERT OR IGNORE INTO crates (name) VALUES (?1)")?
+            .execute(params![name])?;
+        conn.prepare_cached("SELECT id FROM crates WHERE name = ?1")?
+            .query_row(params![name], |row| row.get(0))
+    }
+
+    fn version_id(conn: &Connection, crate_id: i64, version: &str) -> rusqlite::Result<i64> {
+        conn.prepare_cached("INSERT OR IGNORE INTO crate_versions (crate_id, version)
+                             VALUES (?1, ?2)")?
+            .execute(params![crate_id, version])?;
+        conn.prepare_cached("SELECT id FROM crate_versions WHERE crate_id = ?1 AND version = ?2")?
+            .query_row(params![crate_id, version], |row| row.get(0))
+    }
+
+    /// Writes each download between `from` and `to` to `out` as it is read,
+    /// oldest first. Downloads that have been rolled up are left out.
+    pub fn export(&self,
+                  from: i64,
+                  to: i64,
+                  format: ExportFormat,
+                  out: &mut dyn Write)
+                  -> Result<u64, Box<dyn Error>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT downloads.time, crates.name, crate_versions.version,
+                                            downloads.hit, downloads.size,
+                                            downloads.duration_ms, downloads.upstream_ms,
+                                            downloads.remote_addr, downloads.user_agent
+                                     FROM downloads
+                                     INNER JOIN crate_versions
+                                         ON crate_versions.id = downloads.version_id
+                                     INNER JOIN crates ON crates.id = crate_versions.crate_id
+                                     WHERE downloads.time >= ?1 AND downloads.time < ?2
+                                     ORDER BY downloads.time, downloads.rowid")?;
+        let rows = stmt.query_map(params![from, to], |row| {
+            let time: i64 = row.get(0)?;
+            Ok(ExportedDownload {
+                time: humantime::format_rfc3339_seconds(UNIX_EPOCH +
+                                                        Duration::from_secs(time as u64))
+                    .to_string(),
+                name: row.get(1)?,
+                version: row.get(2)?,
+                hit: row.get(3)?,
+                size: row.get(4)?,
+                duration_ms: row.get(5)?,
+                upstream_ms: row.get(6)?,
+                remote_addr: row.get(7)?,
+                user_agent: row.get(8)?,
+            })
+        })?;
+        if format == ExportFormat::Csv {
+            out.write_all(CSV_HEADER.as_bytes())?;
+        }
+        let mut exported = 0;
+        for row in rows {
+            let row = row?;
+            match format {
+                ExportFormat::Csv => row.write_csv(out)?,
+                ExportFormat::Ndjson => {
+                    serde_json::to_writer(&mut *out, &row)?;
+                    out.write_all(b"\n")?;
+                }
             }
+            exported += 1;
         }
-        0
+        out.flush()?;
+        Ok(exported)
     }
 
-    fn crate_id<T: Into<String>>(&self, name: T) -> Option<i32> {
-        let mut stmt = self.conn.prepare("SELECT id FROM crates WHERE name = $1").unwrap();
-        let rows = stmt.query_map(&[&name.into()], |row| row.get(0)).unwrap();
-        for record in rows {
-            if let Ok(id) = record {
-                return Some(id);
-            }
+    /// Replaces the downloads before `before` with daily totals for each
+    /// crate version. Returns how many downloads were rolled up.
+    pub fn roll_up(&self, before: i64) -> Result<usize, Box<dyn Error>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        tx.execute("INSERT INTO download_rollups (version_id, day, downloads, hits, bytes_served,
+                                                  bytes_saved, last_time)
+                    SELECT version_id, time - time % 86400, count(*), SUM(hit), SUM(size),
+                           SUM(CASE WHEN hit THEN size ELSE 0 END), MAX(time)
+                    FROM downloads
+                    WHERE time < ?1
+                    GROUP BY 1, 2
+                    ON CONFLICT (version_id, day) DO UPDATE SET
+                        downloads = downloads + excluded.downloads,
+                        hits = hits + excluded.hits,
+                        bytes_served = bytes_served + excluded.bytes_served,
+                        bytes_saved = bytes_saved + excluded.bytes_saved,
+                        last_time = MAX(last_time, excluded.last_time)",
+                   params![before])?;
+        let rolled_up = tx.execute("DELETE FROM downloads WHERE time < ?1", params![before])?;
+        tx.commit()?;
+        Ok(rolled_up)
+    }
+
+    /// Records a batch of downloads in a single transaction.
+    pub fn add_requests(&self, reqs: &[CargoRequest]) -> Result<(), Box<dyn Error>> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        for req in reqs {
+            let crate_id = Database::crate_id(&tx, &req.name)?;
+            let version_id = Database::version_id(&tx, crate_id, &req.version)?;
+            tx.prepare_cached("INSERT INTO downloads (version_id, time, hit, size, duration_ms,
+                                                      upstream_ms, remote_addr, user_agent)
+                               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?
+                .execute(params![version_id,
+                                 req.time,
+                                 req.hit,
+                                 req.size,
+                                 req.duration_ms,
+                                 req.upstream_ms,
+                                 req.remote_addr,
+                                 req.user_agent])?;
         }
-        return None;
+        tx.commit()?;
+        Ok(())
     }
+}
 
-    fn version_id<T: Into<String>>(&self, crate_id: i32, version: T) -> Option<i32> {
-        let mut stmt = self.conn
-            .prepare("SELECT id
-            FROM crate_versions
-            WHERE crate_id = $1 \
-                      AND version = $2")
-            .unwrap();
-        let rows = stmt.query_map(params![crate_id, version.into()], |row| row.get(0)).unwrap();
-        for record in rows {
-            if let Ok(id) = record {
-                return Some(id);
-            }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
         }
-        return None;
     }
 
-    pub fn add_request<T: Into<String>, S: Into<String>>(&self,
-                                                         crate_name: T,
-                                                         crate_version: S,
-                                                         hit: bool,
-                                                         size: i64)
-                                                         -> Result<(), rusqlite::Error> {
-        let crate_name = crate_name.into();
-        let crate_version = crate_version.into();
-        let _ = self.conn
-            .execute("INSERT OR IGNORE INTO crates (name) VALUES ($1)",
-                     params![crate_name])
-            .unwrap();
-        let crate_id = self.crate_id(crate_name).unwrap();
-        let _ = self.conn
-            .execute("INSERT OR IGNORE INTO crate_versions (crate_id, version) VALUES ($1, $2)",
-                     params![crate_id, crate_version])
-            .unwrap();
-        let version_id = self.version_id(crate_id, crate_version).unwrap();
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
 
-        info!("Version ID: {}", version_id);
-        let _ = self.conn
-            .execute("INSERT INTO downloads (version_id, time, hit, size) VALUES ($1, \
-                      date('now'), $2, $3)",
-                     params![version_id, hit, size]);
-        Ok(())
+impl FromStr for ExportFormat {
+    type Err = String;
 
+    fn from_str(s: &str) -> Result<ExportFormat, String> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            _ => Err(format!("unknown format \"{}\", expected csv or ndjson", s)),
+        }
     }
 }
 
+/// A single download, as exported.
+#[derive(Debug, Serialize)]
+struct ExportedDownload {
+    time: String,
+    name: String,
+    version: String,
+    hit: bool,
+    size: i64,
+    duration_ms: Option<i64>,
+    upstream_ms: Option<i64>,
+    remote_addr: Option<String>,
+    user_agent: Option<String>,
+}
+
+const CSV_HEADER: &str =
+    "time,name,version,hit,size,duration_ms,upstream_ms,remote_addr,user_agent\n";
+
+impl ExportedDownload {
+    fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        let optional = |n: Option<i64>| n.map(|n| n.to_string()).unwrap_or_default();
+        writeln!(out,
+                 "{},{},{},{},{},{},{},{},{}",
+                 self.time,
+                 csv_field(&self.name),
+                 csv_field(&self.version),
+                 self.hit,
+                 self.size,
+                 optional(self.duration_ms),
+                 optional(self.upstream_ms),
+                 csv_field(self.remote_addr.as_deref().unwrap_or("")),
+                 csv_field(self.user_agent.as_deref().unwrap_or("")))
+    }
+}
 
-pub fn stat_collector() -> SyncSender<CargoRequest> {
-    let (sender, receiver) = sync_channel::<CargoRequest>(10);
-    let db = Database::new(None::<&str>);
+/// Quotes a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// How often to look for downloads to roll up.
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Downloads from before this time are old enough to roll up. Whole days
+/// only, so that no day is split between the two tables.
+pub fn rollup_cutoff(retention: Duration) -> i64 {
+    let before = now() - retention.as_secs() as i64;
+    before - before.rem_euclid(Bucket::Day.seconds())
+}
+
+/// Starts the background thread rolling up downloads older than `retention`
+/// into daily totals, which keeps the database small while long term trends
+/// stay queryable.
+pub fn init_rollups(db: &Database, retention: Duration) {
+    let db = db.clone();
     thread::spawn(move || loop {
-        if let Ok(req) = receiver.recv() {
-            info!("Logging a crate request to sqlite: {:?}", req);
-            let _ = db.add_request(req.name, req.version, req.hit, req.size).unwrap();
-        } else {
-            break;
+        let before = rollup_cutoff(retention);
+        match db.roll_up(before) {
+            Ok(0) => {}
+            Ok(n) => info!("Rolled up {} downloads from before {}", n, before),
+            Err(e) => error!("Couldn't roll up old downloads: {}", e),
         }
+        sleep(ROLLUP_INTERVAL);
     });
-    sender
+}
+
+enum Message {
+    Request(CargoRequest),
+    /// Acknowledged once everything sent before it has been written
+    Flush(Sender<()>),
+}
+
+/// Hands downloads to a background thread that writes them to the database
+/// in batches, so that a slow or failing database never holds up a download.
+#[derive(Clone)]
+pub struct Collector {
+    sender: Sender<Message>,
+}
+
+/// The most downloads written in one transaction.
+const MAX_BATCH: usize = 500;
+
+/// How long to wait for the database when flushing.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Collector {
+    pub fn record(&self, req: CargoRequest) {
+        if self.sender.send(Message::Request(req)).is_err() {
+            warn!("The stats collector has stopped, not recording a download");
+        }
+    }
+
+    /// Waits until everything recorded so far is in the database.
+    pub fn flush(&self) {
+        let (ack, done) = channel();
+        if self.sender.send(Message::Flush(ack)).is_ok() && done.recv_timeout(FLUSH_TIMEOUT).is_err() {
+            warn!("Timed out writing statistics to the database");
+        }
+    }
+}
+
+pub fn stat_collector(db: &Database) -> Collector {
+    let (sender, receiver) = channel();
+    let db = db.clone();
+    thread::spawn(move || {
+        while let Ok(message) = receiver.recv() {
+            // Take whatever else is already queued along with it
+            let mut batch = vec![];
+            let mut flushes = vec![];
+            let mut next = Some(message);
+            while let Some(message) = next {
+                match message {
+                    Message::Request(req) => batch.push(req),
+                    Message::Flush(ack) => flushes.push(ack),
+                }
+                next = if batch.len() < MAX_BATCH {
+                    receiver.try_recv().ok()
+                } else {
+                    None
+                };
+            }
+            if !batch.is_empty() {
+                debug!("Logging {} crate requests to sqlite", batch.len());
+                if let Err(e) = db.add_requests(&batch) {
+                    error!("Couldn't record {} downloads in the stats database: {}",
+                           batch.len(),
+                           e);
+                }
+            }
+            for ack in flushes {
+                let _ = ack.send(());
+            }
+        }
+    });
+    Collector { sender }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: i64 = 86400;
+
+    fn memory_database() -> Database {
+        // Every in-memory connection is its own database, so share just one
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        migrate(&mut pool.get().unwrap()).unwrap();
+        Database { pool }
+    }
+
+    fn add_download(db: &Database, time: i64, hit: bool, size: i64) {
+        let conn = db.pool.get().unwrap();
+        let crate_id = Database::crate_id(&conn, "serde").unwrap();
+        let version_id = Database::version_id(&conn, crate_id, "1.0.0").unwrap();
+        conn.execute("INSERT INTO downloads (version_id, time, hit, size) VALUES (?1, ?2, ?3, ?4)",
+                     params![version_id, time, hit, size])
+            .unwrap();
+    }
+
+    /// Downloads, hits, bytes served and bytes saved, rolled up or not.
+    fn totals(db: &Database) -> (i64, i64, i64, i64) {
+        db.pool
+            .get()
+            .unwrap()
+            .query_row("SELECT SUM(downloads), SUM(hits), SUM(bytes_served), SUM(bytes_saved)
+                        FROM all_downloads",
+                       rusqlite::NO_PARAMS,
+                       |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+    }
+
+    fn rollups(db: &Database) -> Vec<(i64, i64, i64)> {
+        let conn = db.pool.get().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT day, downloads, last_time FROM download_rollups ORDER BY day")
+            .unwrap();
+        let rows = stmt
+            .query_map(rusqlite::NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap();
+        rows.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn roll_up_keeps_the_totals() {
+        let db = memory_database();
+        add_download(&db, 10 * DAY + 5, true, 100);
+        add_download(&db, 10 * DAY + 50, false, 200);
+        add_download(&db, 11 * DAY, true, 300);
+        add_download(&db, 20 * DAY, false, 400);
+        let before = totals(&db);
+
+        assert_eq!(db.roll_up(12 * DAY).unwrap(), 3);
+
+        assert_eq!(totals(&db), before);
+        assert_eq!(totals(&db), (4, 2, 1000, 400));
+        assert_eq!(rollups(&db), vec![(10 * DAY, 2, 10 * DAY + 50), (11 * DAY, 1, 11 * DAY)]);
+    }
+
+    #[test]
+    fn roll_up_merges_into_an_existing_day() {
+        let db = memory_database();
+        add_download(&db, 10 * DAY + 5, true, 100);
+        db.roll_up(11 * DAY).unwrap();
+        // Ex: recorded late by a collector flushing at shutdown
+        add_download(&db, 10 * DAY + 60, true, 200);
+        add_download(&db, 10 * DAY + 1, false, 300);
+
+        assert_eq!(db.roll_up(11 * DAY).unwrap(), 2);
+
+        assert_eq!(totals(&db), (3, 2, 600, 300));
+        assert_eq!(rollups(&db), vec![(10 * DAY, 3, 10 * DAY + 60)]);
+    }
 }
@@ -0,0 +1,114 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use hyper;
+use hyper::net::{HttpStream, NetworkStream, SslServer};
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+/// Terminates TLS with the certificate and key given by `--tls-cert` and
+/// `--tls-key`. Clones share the certificate, so `reload` affects every
+/// listener.
+#[derive(Clone)]
+pub struct TlsServer {
+    cert: PathBuf,
+    key: PathBuf,
+    config: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsServer {
+    pub fn new(cert: &str, key: &str) -> Result<TlsServer, String> {
+        let cert = PathBuf::from(cert);
+        let key = PathBuf::from(key);
+        let config = server_config(&cert, &key)?;
+        Ok(TlsServer {
+            cert,
+            key,
+            config: Arc::new(RwLock::new(config)),
+        })
+    }
+
+    /// Reads the certificate and key again, ex: after they were renewed. New
+    /// connections use them, and the old ones are kept if they can't be read.
+    pub fn reload(&self) -> Result<(), String> {
+        let config = server_config(&self.cert, &self.key)?;
+        *self.config.write().unwrap() = config;
+        Ok(())
+    }
+}
+
+fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>, String> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("couldn't read {}: {}", cert.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("couldn't read {}: {}", key.display(), e))?;
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| e.to_string())?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+impl SslServer for TlsServer {
+    type Stream = TlsStream;
+
+    fn wrap_server(&self, stream: HttpStream) -> hyper::Result<TlsStream> {
+        let config = self.config.read().unwrap().clone();
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        // The handshake happens on the first read, on the connection's own
+        // thread, so a slow client can't hold up hyper's accept loop.
+        Ok(TlsStream(Arc::new(Mutex::new(StreamOwned::new(conn, stream)))))
+    }
+}
+
+/// hyper needs to clone streams to read requests and write responses, which
+/// it never does at the same time.
+#[derive(Clone)]
+pub struct TlsStream(Arc<Mutex<StreamOwned<ServerConnection, HttpStream>>>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+impl NetworkStream for TlsStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.lock().unwrap().sock.peer_addr()
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().sock.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.lock().unwrap().sock.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        let mut stream = self.0.lock().unwrap();
+        stream.conn.send_close_notify();
+        let _ = stream.flush();
+        stream.sock.close(how)
+    }
+}